@@ -0,0 +1,645 @@
+// ====================================================
+// Netlyser Copyright(C) 2019 Furkan Türkal
+// This program comes with ABSOLUTELY NO WARRANTY; This is free software,
+// and you are welcome to redistribute it under certain conditions; See
+// file LICENSE, which is part of this source code package, for details.
+// ====================================================
+
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
+
+use pnet::util::MacAddr;
+
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::Marker;
+
+use crate::config;
+use crate::net;
+
+const ROOT_KEYS: &[&str] = &["general", "hosts", "security", "passive", "presence", "networks", "nmap", "people", "groups"];
+const GENERAL_KEYS: &[&str] = &[
+    "interval", "round", "notify_on_connect", "notify_on_disconnect", "notify_on_unknown", "unknown_severity",
+    "ignore_random_unknown", "oui_file", "notify_template", "resolve_hostnames", "mdns_timeout", "resolve_ttl", "scanner",
+    "lease_file", "interfaces", "notify_on_arrive", "notify_on_leave",
+];
+const HOST_KEYS: &[&str] = &["mac", "hostname", "client_id", "name", "device", "tags"];
+const PERSON_KEYS: &[&str] = &["name", "devices"];
+const PERSON_DEVICE_KEYS: &[&str] = &["mac", "hostname", "client_id", "device", "primary", "tags"];
+const GROUP_KEYS: &[&str] = &["notify_on_connect", "notify_on_disconnect", "notify_alerts", "severity"];
+const NETWORK_KEYS: &[&str] = &["network", "label", "interval", "round", "scanner", "lease_file", "interfaces"];
+const NMAP_KEYS: &[&str] = &["probes", "syn_ports", "ack_ports", "timing", "max_retries", "host_timeout"];
+const SECURITY_KEYS: &[&str] = &["mode", "repeat_interval", "severity", "arp_watch", "max_ips_per_mac", "gateway_relearn"];
+const PASSIVE_KEYS: &[&str] = &["enabled"];
+const PRESENCE_KEYS: &[&str] = &["policy", "max_age"];
+
+// 1-based, like the editors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Position {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl From<Marker> for Position {
+    fn from(marker: Marker) -> Self {
+        Position {
+            line: marker.line(),
+            col: marker.col() + 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Problem {
+    pub position: Position,
+    pub message: String,
+}
+
+// YAML document with the positions, serde does not keep them
+enum Node {
+    Scalar(String, Position),
+    Seq(Vec<Node>, Position),
+    Map(Vec<(Node, Node)>, Position),
+    Alias(Position),
+}
+
+impl Node {
+    fn position(&self) -> Position {
+        match self {
+            Node::Scalar(_, p) | Node::Seq(_, p) | Node::Map(_, p) | Node::Alias(p) => *p,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Node::Scalar(s, _) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&Node> {
+        match self {
+            Node::Map(entries, _) => entries.iter().find(|(k, _)| k.as_str() == Some(key)).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+}
+
+enum Frame {
+    Seq(Vec<Node>, Position),
+    // Entries, the key waiting for its value
+    Map(Vec<(Node, Node)>, Option<Node>, Position),
+}
+
+#[derive(Default)]
+struct Builder {
+    stack: Vec<Frame>,
+    root: Option<Node>,
+}
+
+impl Builder {
+    fn push(&mut self, node: Node) {
+        match self.stack.last_mut() {
+            None => self.root = Some(node),
+            Some(Frame::Seq(items, _)) => items.push(node),
+            Some(Frame::Map(entries, key, _)) => match key.take() {
+                Some(k) => entries.push((k, node)),
+                None => *key = Some(node),
+            },
+        }
+    }
+}
+
+impl MarkedEventReceiver for Builder {
+    fn on_event(&mut self, ev: Event, mark: Marker) {
+        let position = Position::from(mark);
+
+        match ev {
+            Event::Scalar(value, _, _, _) => self.push(Node::Scalar(value, position)),
+            Event::Alias(_) => self.push(Node::Alias(position)),
+            Event::SequenceStart(_) => self.stack.push(Frame::Seq(vec![], position)),
+            Event::MappingStart(_) => self.stack.push(Frame::Map(vec![], None, position)),
+            Event::SequenceEnd | Event::MappingEnd => {
+                let node = match self.stack.pop() {
+                    Some(Frame::Seq(items, p)) => Node::Seq(items, p),
+                    // The start of a block mapping is marked after its first key
+                    Some(Frame::Map(entries, _, p)) => {
+                        let p = entries.first().map(|(k, _)| k.position()).unwrap_or(p);
+                        Node::Map(entries, p)
+                    }
+                    None => return,
+                };
+                self.push(node);
+            }
+            _ => {}
+        }
+    }
+}
+
+struct Checker {
+    problems: Vec<Problem>,
+    // Identifier, value -> where it is first defined, over the hosts and the people
+    identifiers: HashMap<(&'static str, String), Position>,
+    // Tags given to the hosts and the people devices
+    tags: HashSet<String>,
+}
+
+impl Checker {
+    fn report(&mut self, position: Position, message: String) {
+        self.problems.push(Problem {
+            position: position,
+            message: message,
+        });
+    }
+
+    fn check_keys(&mut self, node: &Node, section: &str, allowed: &[&str]) {
+        let entries = match node {
+            Node::Map(entries, _) => entries,
+            _ => {
+                self.report(node.position(), format!("'{}' must be a mapping", section));
+                return;
+            }
+        };
+
+        let mut seen: HashMap<&str, Position> = HashMap::new();
+
+        for (key, _) in entries {
+            let name = match key.as_str() {
+                Some(r) => r,
+                None => {
+                    self.report(key.position(), format!("keys of '{}' must be strings", section));
+                    continue;
+                }
+            };
+            if !allowed.contains(&name) {
+                self.report(key.position(), format!("unknown key '{}' in '{}', expected one of: {}", name, section, allowed.join(", ")));
+            }
+            if let Some(first) = seen.get(name) {
+                self.report(key.position(), format!("duplicate key '{}' in '{}', first defined at line {}", name, section, first.line));
+            }
+            seen.entry(name).or_insert_with(|| key.position());
+        }
+    }
+
+    fn check_required(&mut self, node: &Node, section: &str, required: &[&str]) {
+        for key in required {
+            if node.get(key).is_none() {
+                self.report(node.position(), format!("missing key '{}' in '{}'", key, section));
+            }
+        }
+    }
+
+    fn check_interval(&mut self, node: &Node, section: &str) {
+        let value = match node.get("interval") {
+            Some(r) => r,
+            None => return,
+        };
+        match value.as_str().map(|s| s.parse::<u64>()) {
+            Some(Ok(0)) => self.report(value.position(), format!("'interval' of '{}' must be greater than 0", section)),
+            Some(Ok(_)) => {}
+            _ => self.report(value.position(), format!("'interval' of '{}' must be a number of milliseconds", section)),
+        }
+    }
+
+    fn check_general(&mut self, node: &Node) {
+        self.check_keys(node, "general", GENERAL_KEYS);
+        self.check_required(node, "general", &["interval", "round", "notify_on_connect", "notify_on_disconnect"]);
+        self.check_interval(node, "general");
+    }
+
+    fn check_hosts(&mut self, node: &Node) {
+        let hosts = match node {
+            Node::Seq(items, _) => items,
+            _ => {
+                self.report(node.position(), "'hosts' must be a list".to_string());
+                return;
+            }
+        };
+
+        for (i, host) in hosts.iter().enumerate() {
+            let section = format!("hosts[{}]", i);
+
+            self.check_keys(host, &section, HOST_KEYS);
+            self.check_required(host, &section, &["name", "device"]);
+            self.check_identifiers(host, &section);
+            self.check_tags(host, &section);
+        }
+    }
+
+    fn check_people(&mut self, node: &Node) {
+        let people = match node {
+            Node::Seq(items, _) => items,
+            _ => {
+                self.report(node.position(), "'people' must be a list".to_string());
+                return;
+            }
+        };
+
+        for (i, person) in people.iter().enumerate() {
+            let section = format!("people[{}]", i);
+
+            self.check_keys(person, &section, PERSON_KEYS);
+            self.check_required(person, &section, &["name", "devices"]);
+
+            let devices = match person.get("devices") {
+                Some(Node::Seq(items, _)) => items,
+                Some(n) => {
+                    self.report(n.position(), format!("'devices' of '{}' must be a list", section));
+                    continue;
+                }
+                None => continue,
+            };
+
+            for (j, device) in devices.iter().enumerate() {
+                let section = format!("people[{}].devices[{}]", i, j);
+
+                self.check_keys(device, &section, PERSON_DEVICE_KEYS);
+                self.check_required(device, &section, &["device"]);
+                self.check_identifiers(device, &section);
+                self.check_tags(device, &section);
+            }
+        }
+    }
+
+    fn check_identifiers(&mut self, host: &Node, section: &str) {
+        if host.get("mac").is_none() && host.get("hostname").is_none() && host.get("client_id").is_none() {
+            self.report(host.position(), format!("'{}' needs one of 'mac', 'hostname' or 'client_id'", section));
+        }
+
+        for key in &["mac", "hostname", "client_id"] {
+            let value = match host.get(key) {
+                Some(r) => r,
+                None => continue,
+            };
+
+            let normalized = match (*key, value.as_str()) {
+                ("mac", Some(s)) => match MacAddr::from_str(s) {
+                    Ok(m) => m.to_string(),
+                    Err(_) => {
+                        self.report(value.position(), format!("invalid MAC address '{}', it must be like '01:23:45:67:89:AB'", s));
+                        continue;
+                    }
+                },
+                ("hostname", Some(s)) => config::normalize_hostname(s),
+                (_, Some(s)) => s.to_lowercase(),
+                (_, None) => {
+                    self.report(value.position(), format!("'{}' of '{}' must be a string", key, section));
+                    continue;
+                }
+            };
+
+            match self.identifiers.get(&(*key, normalized.clone())).cloned() {
+                Some(first) => self.report(value.position(), format!("duplicate {} '{}', first defined at line {}", key, value.as_str().unwrap_or(""), first.line)),
+                None => {
+                    self.identifiers.insert((*key, normalized), value.position());
+                }
+            }
+        }
+    }
+
+    fn check_tags(&mut self, host: &Node, section: &str) {
+        let tags = match host.get("tags") {
+            Some(Node::Seq(items, _)) => items,
+            Some(n) => {
+                self.report(n.position(), format!("'tags' of '{}' must be a list", section));
+                return;
+            }
+            None => return,
+        };
+
+        for tag in tags {
+            match tag.as_str() {
+                Some(s) if !s.is_empty() && !s.contains(',') => {
+                    self.tags.insert(s.to_string());
+                }
+                _ => self.report(tag.position(), format!("tags of '{}' must be non-empty strings without a comma", section)),
+            }
+        }
+    }
+
+    // Checked after the hosts and the people to find the groups those match no tag
+    fn check_groups(&mut self, node: &Node) {
+        let groups = match node {
+            Node::Map(entries, _) => entries,
+            _ => {
+                self.report(node.position(), "'groups' must be a mapping of the tags".to_string());
+                return;
+            }
+        };
+
+        for (key, group) in groups {
+            let name = key.as_str().unwrap_or("");
+            let section = format!("groups.{}", name);
+
+            self.check_keys(group, &section, GROUP_KEYS);
+
+            if !self.tags.contains(name) {
+                self.report(key.position(), format!("group '{}' is not a tag of any host", name));
+            }
+        }
+    }
+
+    fn check_networks(&mut self, node: &Node) {
+        let networks = match node {
+            Node::Seq(items, _) => items,
+            _ => {
+                self.report(node.position(), "'networks' must be a list".to_string());
+                return;
+            }
+        };
+
+        for (i, network) in networks.iter().enumerate() {
+            let section = format!("networks[{}]", i);
+
+            self.check_keys(network, &section, NETWORK_KEYS);
+            self.check_required(network, &section, &["network"]);
+            self.check_interval(network, &section);
+
+            if let Some(value) = network.get("network") {
+                match value.as_str() {
+                    Some(s) if net::ScanInfo::from_str(s).is_ok() => {}
+                    _ => self.report(value.position(), format!("'network' of '{}' must be in CIDR notation like '192.168.1.0/24'", section)),
+                }
+            }
+        }
+    }
+
+    // The rules those need the typed config
+    fn check_config(&mut self, root: &Node, config: &config::Config) {
+        if let Some(node) = root.get("nmap") {
+            if !config.nmap.is_valid() {
+                self.report(node.position(), "invalid 'nmap' options, 'probes' must not be empty, the ports must not be 0, 'timing' must be 0-5 and 'host_timeout' must be like '30s'".to_string());
+            }
+        }
+
+        let general = root.get("general").map(|n| n.position()).unwrap_or(root.position());
+        if config.general.scanner == config::Scanner::Leases && config.general.lease_file.is_none() {
+            self.report(general, "'general' uses the 'leases' scanner without a 'lease_file'".to_string());
+        }
+
+        let nodes: Vec<Position> = match root.get("networks") {
+            Some(Node::Seq(items, _)) => items.iter().map(|n| n.position()).collect(),
+            _ => vec![],
+        };
+        for (i, (network, position)) in config.networks.iter().zip(nodes).enumerate() {
+            if network.get_scanner(&config.general) == config::Scanner::Leases && network.get_lease_file(&config.general).is_none() {
+                self.report(position, format!("'networks[{}]' uses the 'leases' scanner without a 'lease_file'", i));
+            }
+        }
+    }
+}
+
+// Returns every problem of the config, sorted by their position
+pub fn check_config(content: &str) -> Vec<Problem> {
+    let mut checker = Checker {
+        problems: vec![],
+        identifiers: HashMap::new(),
+        tags: HashSet::new(),
+    };
+
+    let mut builder = Builder::default();
+    if let Err(e) = Parser::new(content.chars()).load(&mut builder, false) {
+        checker.report(Position::from(*e.marker()), format!("invalid YAML: {}", e));
+        return checker.problems;
+    }
+
+    let root = match builder.root {
+        Some(r) => r,
+        None => {
+            checker.report(Position { line: 1, col: 1 }, "config is empty".to_string());
+            return checker.problems;
+        }
+    };
+
+    checker.check_keys(&root, "config", ROOT_KEYS);
+    checker.check_required(&root, "config", &["general", "hosts"]);
+
+    if let Some(node) = root.get("general") {
+        checker.check_general(node);
+    }
+    if let Some(node) = root.get("hosts") {
+        checker.check_hosts(node);
+    }
+    if let Some(node) = root.get("people") {
+        checker.check_people(node);
+    }
+    if let Some(node) = root.get("groups") {
+        checker.check_groups(node);
+    }
+    if let Some(node) = root.get("networks") {
+        checker.check_networks(node);
+    }
+
+    let sections: [(&str, &[&str]); 4] = [
+        ("security", SECURITY_KEYS),
+        ("passive", PASSIVE_KEYS),
+        ("presence", PRESENCE_KEYS),
+        ("nmap", NMAP_KEYS),
+    ];
+    for (section, keys) in sections.iter() {
+        if let Some(node) = root.get(section) {
+            checker.check_keys(node, section, keys);
+        }
+    }
+
+    // The types (i.e. a bool given as a string) are checked by serde, its
+    // errors are skipped on the lines those already have a problem
+    match serde_yaml::from_str::<config::Config>(content) {
+        Ok(config) => checker.check_config(&root, &config),
+        Err(e) => {
+            let message = e.to_string();
+            let position = match e.location() {
+                Some(l) => Position { line: l.line(), col: l.column() },
+                None => root.position(),
+            };
+            if !checker.problems.iter().any(|p| p.position.line == position.line) {
+                let suffix = format!(" at line {} column {}", position.line, position.col);
+                checker.report(position, message.trim_end_matches(suffix.as_str()).to_string());
+            }
+        }
+    }
+
+    checker.problems.sort_by_key(|p| (p.position.line, p.position.col));
+
+    checker.problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use crate::serde_yaml;
+
+    const GENERAL: &str = "general:\n  interval: 1000\n  round: 1\n  notify_on_connect: true\n  notify_on_disconnect: true\n";
+
+    fn full() -> String {
+        fs::read_to_string("data/fixtures/config/full.yaml").unwrap()
+    }
+
+    fn keys(node: &Node) -> Vec<&str> {
+        let mut keys: Vec<&str> = match node {
+            Node::Map(entries, _) => entries.iter().filter_map(|(k, _)| k.as_str()).collect(),
+            _ => vec![],
+        };
+        keys.sort();
+        keys
+    }
+
+    fn first(node: Option<&Node>) -> Option<&Node> {
+        match node {
+            Some(Node::Seq(items, _)) => items.first(),
+            _ => None,
+        }
+    }
+
+    fn sorted(keys: &[&'static str]) -> Vec<&'static str> {
+        let mut keys = keys.to_vec();
+        keys.sort();
+        keys
+    }
+
+    fn problem(content: &str) -> Problem {
+        let problems = check_config(content);
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        problems[0].clone()
+    }
+
+    #[test]
+    fn full_config_has_no_problems() {
+        let problems = check_config(&full());
+
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+
+    // A key added to a struct but not to the tables is reported as unknown by
+    // the test above, a key in the tables but not read by serde is
+    // caught by the values below
+    #[test]
+    fn full_config_lists_every_key() {
+        let content = full();
+        let mut builder = Builder::default();
+        Parser::new(content.chars()).load(&mut builder, false).unwrap();
+        let root = builder.root.unwrap();
+
+        let person = first(root.get("people")).unwrap();
+        let sections: Vec<(&str, Option<&Node>, &[&'static str])> = vec![
+            ("config", Some(&root), ROOT_KEYS),
+            ("general", root.get("general"), GENERAL_KEYS),
+            ("hosts", first(root.get("hosts")), HOST_KEYS),
+            ("people", Some(person), PERSON_KEYS),
+            ("devices", first(person.get("devices")), PERSON_DEVICE_KEYS),
+            ("groups", root.get("groups").and_then(|g| g.get("kids")), GROUP_KEYS),
+            ("networks", first(root.get("networks")), NETWORK_KEYS),
+            ("nmap", root.get("nmap"), NMAP_KEYS),
+            ("security", root.get("security"), SECURITY_KEYS),
+            ("passive", root.get("passive"), PASSIVE_KEYS),
+            ("presence", root.get("presence"), PRESENCE_KEYS),
+        ];
+
+        for (section, node, allowed) in sections {
+            assert_eq!(keys(node.unwrap()), sorted(allowed), "keys of '{}'", section);
+        }
+    }
+
+    #[test]
+    fn full_config_sets_every_field() {
+        let config: config::Config = serde_yaml::from_str(&full()).unwrap();
+
+        let general = &config.general;
+        assert_eq!((general.interval, general.round), (30000, 3));
+        assert!(general.notify_on_connect && !general.notify_on_disconnect);
+        assert!(!general.notify_on_unknown);
+        assert_eq!(general.unknown_severity, config::Severity::Normal);
+        assert!(general.ignore_random_unknown);
+        assert_eq!(general.oui_file, Some("data/oui.txt".to_string()));
+        assert_eq!(general.notify_template, "{name} on {network}");
+        assert!(!general.resolve_hostnames);
+        assert_eq!((general.mdns_timeout, general.resolve_ttl), (500, 60000));
+        assert_eq!(general.scanner, config::Scanner::Nmap);
+        assert_eq!(general.lease_file, Some("/var/lib/misc/dnsmasq.leases".to_string()));
+        assert_eq!(general.interfaces, vec!["eth0".to_string()]);
+        assert!(!general.notify_on_arrive && !general.notify_on_leave);
+
+        let network = &config.networks[0];
+        assert_eq!(network.network, "10.0.20.0/24");
+        assert_eq!(network.label, Some("iot".to_string()));
+        assert_eq!((network.interval, network.round), (Some(60000), Some(2)));
+        assert_eq!(network.scanner, Some(config::Scanner::Leases));
+        assert_eq!(network.lease_file, Some("/var/lib/dhcp/dhcpd.leases".to_string()));
+        assert_eq!(network.interfaces, vec!["eth0.20".to_string()]);
+
+        let nmap = &config.nmap;
+        assert_eq!(nmap.probes, vec![config::Probe::Arp, config::Probe::Syn, config::Probe::Ack]);
+        assert_eq!((nmap.syn_ports.clone(), nmap.ack_ports.clone()), (vec![22, 443], vec![80]));
+        assert_eq!((nmap.timing, nmap.max_retries), (Some(3), Some(1)));
+        assert_eq!(nmap.host_timeout, Some("10s".to_string()));
+
+        let security = &config.security;
+        assert_eq!(security.mode, config::SecurityMode::Allowlist);
+        assert_eq!(security.repeat_interval, 120000);
+        assert_eq!(security.severity, config::Severity::Low);
+        assert!(!security.arp_watch);
+        assert_eq!((security.max_ips_per_mac, security.gateway_relearn), (8, 0));
+
+        assert!(config.passive.enabled);
+        assert_eq!(config.presence.policy, config::RestorePolicy::Silent);
+        assert_eq!(config.presence.max_age, 3600000);
+
+        let group = &config.groups["kids"];
+        assert_eq!((group.notify_on_connect, group.notify_on_disconnect), (Some(false), Some(false)));
+        assert_eq!(group.notify_alerts, Some(false));
+        assert_eq!(group.severity, Some(config::Severity::Low));
+
+        // The fields of the hosts and the people are private, they are read through the map
+        let map = config::get_host_map(config);
+        let unknown = MacAddr::from_str("00:00:00:00:00:01").unwrap();
+        for (mac, hostname, client_id, name) in &[
+            ("01:23:45:67:89:AB", "desktop", "01:01:23:45:67:89:ab", "Furkan"),
+            ("02:23:45:67:89:AB", "alice-phone", "01:02:23:45:67:89:ab", "Alice"),
+        ] {
+            let mac = MacAddr::from_str(mac).unwrap();
+            assert_eq!(map.find(&mac, None, None).unwrap().name, *name);
+            assert_eq!(map.find(&unknown, Some(hostname), None).unwrap().name, *name);
+            assert_eq!(map.find(&unknown, None, Some(client_id)).unwrap().name, *name);
+        }
+
+        let alice = map.find(&MacAddr::from_str("02:23:45:67:89:AB").unwrap(), None, None).unwrap();
+        assert_eq!(alice.device_name, "Phone");
+        assert_eq!(alice.person, Some("Alice".to_string()));
+        assert!(!alice.primary);
+        assert_eq!(alice.tags, vec!["kids".to_string()]);
+    }
+
+    #[test]
+    fn reports_invalid_mac_position() {
+        let content = format!("{}hosts:\n  - name: a\n    device: b\n    mac: \"01:23:45:67:89\"\n", GENERAL);
+        let p = problem(&content);
+
+        assert_eq!(p.position, Position { line: 9, col: 10 });
+        assert_eq!(p.message, "invalid MAC address '01:23:45:67:89', it must be like '01:23:45:67:89:AB'");
+    }
+
+    #[test]
+    fn reports_duplicate_mac_position() {
+        let content = format!(
+            "{}hosts:\n  - mac: \"01:23:45:67:89:AB\"\n    name: a\n    device: b\n  - name: c\n    device: d\n    mac: \"01:23:45:67:89:ab\"\n",
+            GENERAL
+        );
+        let p = problem(&content);
+
+        assert_eq!(p.position, Position { line: 12, col: 10 });
+        assert_eq!(p.message, "duplicate mac '01:23:45:67:89:ab', first defined at line 7");
+    }
+
+    #[test]
+    fn reports_unknown_key_position() {
+        let content = format!("{}  notify_on_conect: true\nhosts: []\n", GENERAL);
+        let p = problem(&content);
+
+        assert_eq!(p.position, Position { line: 6, col: 3 });
+        assert!(p.message.starts_with("unknown key 'notify_on_conect' in 'general'"), "{}", p.message);
+    }
+}
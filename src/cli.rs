@@ -8,19 +8,38 @@
 use clap::*;
 use regex::Regex;
 
+use pnet::util::MacAddr;
+
 use crate::error;
+use crate::oui;
 
 use std::{
     ffi::OsString,
+    path::Path,
+    str::FromStr,
 };
 
+#[derive(Clone, Debug)]
+pub enum Command {
+    Monitor,
+    Alerts(Option<String>),
+    History(Option<String>, u32),
+    Ack(Option<String>),
+    Replay(String),
+    CheckConfig,
+    // Download URL of the OUI registry
+    UpdateOui(String),
+}
+
 #[derive(Clone, Debug)]
 pub struct Args {
     pub quiet: bool,
     pub verbose: u64,
     pub network: String,
+    pub interfaces: Vec<String>,
     pub path_config: String,
-    pub path_output: String
+    pub path_output: String,
+    pub command: Command
 }
 
 pub fn get_args() -> error::Result<Args> {
@@ -36,6 +55,7 @@ where
     let app = App::new("netlyser")
         .version(crate_version!())
         .about("Network observing tool for your sweet home")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(Arg::with_name("quiet")
              .help("Quiet mode (Overrides verbose mode)")
              .short("q")
@@ -53,14 +73,23 @@ where
              .required(false))
 
         .arg(Arg::with_name("network")
-             .help("CIDR notation of the network you want to scan, e.g.'192.168.1.0/24'")
+             .help("CIDR notation of the network you want to scan, e.g.'192.168.1.0/24' (Overrides the networks of the config)")
              .short("n")
              .long("network")
              .takes_value(true)
              .multiple(false)
-             .required(true)
+             .required(false)
              .validator(is_ipmask))
 
+        .arg(Arg::with_name("interface")
+             .help("Interface to scan the network through, e.g. 'eth0' (Can be given multiple times, overrides the interfaces of every network of the config if no '--network' is given)")
+             .short("i")
+             .long("interface")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .required(false))
+
         .arg(Arg::with_name("config-file")
              .help("Input filepath for the config file, e.g '~/.config/netlyser.conf")
              .short("c")
@@ -76,16 +105,117 @@ where
              .long("output-path")
              .takes_value(true)
              .multiple(false)
-             .required(true));
+             .required(true))
+
+        .subcommand(SubCommand::with_name("alerts")
+             .about("Lists the alerts those are not acknowledged yet")
+             .arg(Arg::with_name("tag")
+                  .help("Lists only the alerts of the devices those have the tag, e.g. 'iot'")
+                  .short("t")
+                  .long("tag")
+                  .takes_value(true)
+                  .multiple(false)
+                  .required(false)))
+
+        .subcommand(SubCommand::with_name("history")
+             .about("Lists the latest connects and disconnects")
+             .arg(Arg::with_name("tag")
+                  .help("Lists only the devices those have the tag, e.g. 'kids'")
+                  .short("t")
+                  .long("tag")
+                  .takes_value(true)
+                  .multiple(false)
+                  .required(false))
+             .arg(Arg::with_name("limit")
+                  .help("Number of the entries to list")
+                  .short("l")
+                  .long("limit")
+                  .takes_value(true)
+                  .multiple(false)
+                  .required(false)
+                  .default_value("50")
+                  .validator(is_number)))
+
+        .subcommand(SubCommand::with_name("ack")
+             .about("Acknowledges the alerts to stop repeating them")
+             .arg(Arg::with_name("mac")
+                  .help("MAC address of the device to acknowledge, e.g. '01:23:45:67:89:AB' (Acknowledges all if omitted)")
+                  .takes_value(true)
+                  .multiple(false)
+                  .required(false)
+                  .validator(is_mac)))
+
+        .subcommand(SubCommand::with_name("replay")
+             .about("Replays the recorded nmap XML outputs, ARP and route snapshots without notifying, e.g. '1557093046.xml', '1557093046.arp', '1557093046.route'")
+             .arg(Arg::with_name("dir")
+                  .help("Directory of the recordings those are named by their unix timestamp")
+                  .takes_value(true)
+                  .multiple(false)
+                  .required(true)
+                  .validator(is_dir)))
+
+        .subcommand(SubCommand::with_name("config")
+             .about("Manages the config file")
+             .setting(AppSettings::SubcommandRequiredElseHelp)
+             .subcommand(SubCommand::with_name("check")
+                  .about("Reports every problem of the config file with its line and column")))
+
+        .subcommand(SubCommand::with_name("oui")
+             .about("Manages the OUI vendor database")
+             .setting(AppSettings::SubcommandRequiredElseHelp)
+             .subcommand(SubCommand::with_name("update")
+                  .about("Downloads the IEEE vendor list to the 'oui_file' of the config")
+                  .arg(Arg::with_name("url")
+                       .help("Download URL of the list, e.g. 'https://standards-oui.ieee.org/oui/oui.txt'")
+                       .long("url")
+                       .takes_value(true)
+                       .multiple(false)
+                       .required(false))));
 
     let args = match from {
         None => app.get_matches(),
         Some(i) => app.get_matches_from(i),
     };
 
-    let network: String = value_t!(args.value_of("network"), String)?;
-    let path_config: String = value_t!(args.value_of("config-file"), String)?;
-    let path_output: String = value_t!(args.value_of("output-path"), String)?;
+    let command = match args.subcommand() {
+        ("alerts", Some(sub)) => Command::Alerts(sub.value_of("tag").map(String::from)),
+        // Validated by clap
+        ("history", Some(sub)) => Command::History(sub.value_of("tag").map(String::from), sub.value_of("limit").unwrap().parse().unwrap()),
+        ("ack", Some(sub)) => Command::Ack(sub.value_of("mac").map(String::from)),
+        // Validated by clap
+        ("replay", Some(sub)) => Command::Replay(sub.value_of("dir").unwrap().to_string()),
+        // 'check' is the only subcommand of 'config'
+        ("config", Some(_)) => Command::CheckConfig,
+        // 'update' is the only subcommand of 'oui'
+        ("oui", Some(sub)) => {
+            let url = sub.subcommand_matches("update").and_then(|s| s.value_of("url")).unwrap_or(oui::IEEE_URL);
+            Command::UpdateOui(url.to_string())
+        }
+        _ => Command::Monitor,
+    };
+
+    // Nothing is written to the database when checking the config or updating the OUI file
+    let path_output: String = match (args.value_of("output-path"), &command) {
+        (Some(r), _) => r.to_string(),
+        (None, Command::CheckConfig) | (None, Command::UpdateOui(_)) => String::new(),
+        // A new database is created in the temp directory
+        (None, Command::Replay(_)) => String::new(),
+        (None, _) => return Err(missing_arg("--output-path <output-path>")),
+    };
+
+    // The networks of the config are used if omitted
+    let network: String = args.value_of("network").unwrap_or("").to_string();
+    // Required by clap unless a subcommand is given
+    let path_config: String = args.value_of("config-file").unwrap_or("").to_string();
+
+    match command {
+        Command::Replay(_) | Command::CheckConfig | Command::UpdateOui(_) if path_config.is_empty() => {
+            return Err(missing_arg("--config-file <config-file>"));
+        }
+        _ => {}
+    }
+
+    let interfaces: Vec<String> = args.values_of("interface").map(|v| v.map(String::from).collect()).unwrap_or_default();
 
     match args.occurrences_of("verbose") {
         1 => println!("Verbose: Warn"),
@@ -99,12 +229,21 @@ where
         quiet: args.is_present("quiet"),
         verbose: args.occurrences_of("verbose"),
         network: network,
+        interfaces: interfaces,
         path_config: path_config,
-        path_output: path_output
+        path_output: path_output,
+        command: command
     })
 
 }
 
+fn missing_arg(arg: &str) -> error::Error {
+    error::Error::Clap(clap::Error::with_description(
+        &format!("The following required arguments were not provided:\n    {}", arg),
+        ErrorKind::MissingRequiredArgument,
+    ))
+}
+
 fn is_ipmask(val: String) -> std::result::Result<(), String> {
     lazy_static! {
         static ref RGX_IPMASK: Regex = Regex::new(r"^((?:[0-9]{1,3}\.){3}[0-9]{1,3}/[1-3]?[0-9])$").unwrap();
@@ -128,3 +267,25 @@ fn is_file_yaml(val: String) -> std::result::Result<(), String> {
         Err(String::from("the config file format must be like '.../path/to/config.yaml'"))
     }
 }
+
+fn is_mac(val: String) -> std::result::Result<(), String> {
+    match MacAddr::from_str(&val) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(String::from("the MAC address format must be like '01:23:45:67:89:AB'")),
+    }
+}
+
+fn is_number(val: String) -> std::result::Result<(), String> {
+    match val.parse::<u32>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(String::from("the value must be a positive number")),
+    }
+}
+
+fn is_dir(val: String) -> std::result::Result<(), String> {
+    if Path::new(&val).is_dir() {
+        Ok(())
+    } else {
+        Err(String::from("the directory does not exist"))
+    }
+}
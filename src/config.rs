@@ -7,6 +7,9 @@
 
 use crate::serde_yaml;
 
+use crate::check;
+use crate::error::Error;
+
 use std::{collections::HashMap, fmt, fmt::Display, fs::OpenOptions, io::Read, str::FromStr};
 
 use pnet::util::MacAddr;
@@ -35,6 +38,82 @@ pub enum AwarnessType {
     Unknown = 1,
 }
 
+// Urgency of a notification, mapped to the D-Bus urgency levels
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Low,
+    Normal,
+    Critical,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Low => "low",
+            Severity::Normal => "normal",
+            Severity::Critical => "critical",
+        }
+    }
+}
+
+impl FromStr for Severity {
+    type Err = ErrorType;
+
+    fn from_str(s: &str) -> Result<Severity, ErrorType> {
+        match s {
+            "low" => Ok(Severity::Low),
+            "normal" => Ok(Severity::Normal),
+            "critical" => Ok(Severity::Critical),
+            _ => Err(ErrorType::ParseError),
+        }
+    }
+}
+
+// monitor  : Devices out of the config are only reported as unknown
+// allowlist: Devices out of the config raise an intruder alarm until acknowledged
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SecurityMode {
+    Monitor,
+    Allowlist,
+}
+
+// nmap  : Hosts are discovered by the nmap scans
+// leases: Hosts are discovered from the DHCP lease file without any scanning
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scanner {
+    Nmap,
+    Leases,
+}
+
+// Host discovery probes of nmap
+// arp : ARP ping, '-PR'
+// icmp: ICMP echo, '-PE'
+// syn : TCP SYN ping to the 'syn_ports', '-PS'
+// ack : TCP ACK ping to the 'ack_ports', '-PA'
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Probe {
+    Arp,
+    Icmp,
+    Syn,
+    Ack,
+}
+
+// How the devices those changed state while the monitor is down are reported
+// report: Logged and notified as connected / disconnected
+// silent: Logged but not notified
+// reset : The last state is not restored, every present device is connected again
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RestorePolicy {
+    Report,
+    Silent,
+    Reset,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ErrorType {
     ReadError,
@@ -44,7 +123,11 @@ pub enum ErrorType {
 
 impl Display for ErrorType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self)
+        match self {
+            ErrorType::ReadError => write!(f, "config file does not exist at the given location"),
+            ErrorType::SerdeError => write!(f, "config file is not a valid YAML, run 'netlyser -c <config-file> config check' to see the problems"),
+            ErrorType::ParseError => write!(f, "config is invalid, run 'netlyser -c <config-file> config check' to see the problems"),
+        }
     }
 }
 
@@ -65,8 +148,34 @@ pub struct Config {
     pub general: General,
     pub hosts: Vec<Host>,
 
+    #[serde(default)]
+    pub people: Vec<Person>,
+
+    // Notification rules by the tags of the hosts
+    #[serde(default)]
+    pub groups: HashMap<String, Group>,
+
+    #[serde(default)]
+    pub security: Security,
+
+    #[serde(default)]
+    pub passive: Passive,
+
+    #[serde(default)]
+    pub presence: Presence,
+
+    #[serde(default)]
+    pub networks: Vec<Network>,
+
+    #[serde(default)]
+    pub nmap: Nmap,
+
     #[serde(skip)]
     pub is_root: bool,
+
+    // Notifications are printed instead of shown, i.e. when replaying
+    #[serde(skip)]
+    pub dry_run: bool,
 }
 
 #[derive(Clone, Deserialize, Debug)]
@@ -75,63 +184,517 @@ pub struct General {
     pub round: u8,
     pub notify_on_connect: bool,
     pub notify_on_disconnect: bool,
+
+    #[serde(default = "get_true")]
+    pub notify_on_unknown: bool,
+
+    #[serde(default = "get_critical")]
+    pub unknown_severity: Severity,
+
+    #[serde(default)]
+    pub ignore_random_unknown: bool,
+
+    #[serde(default)]
+    pub oui_file: Option<String>,
+
+    #[serde(default = "get_notify_template")]
+    pub notify_template: String,
+
+    #[serde(default = "get_true")]
+    pub resolve_hostnames: bool,
+
+    #[serde(default = "get_mdns_timeout")]
+    pub mdns_timeout: u64,
+
+    // How long a resolved name (or a failure) is cached, in ms
+    #[serde(default = "get_resolve_ttl")]
+    pub resolve_ttl: u64,
+
+    #[serde(default = "get_nmap")]
+    pub scanner: Scanner,
+
+    #[serde(default)]
+    pub lease_file: Option<String>,
+
+    #[serde(default)]
+    pub interfaces: Vec<String>,
+
+    #[serde(default = "get_true")]
+    pub notify_on_arrive: bool,
+
+    #[serde(default = "get_true")]
+    pub notify_on_leave: bool,
+}
+
+// A network to watch, i.e. a VLAN. The omitted fields fall back to the 'general' ones
+#[derive(Clone, Deserialize, Debug)]
+pub struct Network {
+    pub network: String,
+
+    #[serde(default)]
+    pub label: Option<String>,
+
+    #[serde(default)]
+    pub interval: Option<u64>,
+
+    #[serde(default)]
+    pub round: Option<u8>,
+
+    #[serde(default)]
+    pub scanner: Option<Scanner>,
+
+    #[serde(default)]
+    pub lease_file: Option<String>,
+
+    #[serde(default)]
+    pub interfaces: Vec<String>,
 }
 
+impl Network {
+    // The network given by the cli, scanned with the 'general' settings
+    pub fn new(network: &str, interfaces: Vec<String>) -> Network {
+        Network {
+            network: network.to_string(),
+            label: None,
+            interval: None,
+            round: None,
+            scanner: None,
+            lease_file: None,
+            interfaces: interfaces,
+        }
+    }
+
+    pub fn get_label(&self) -> String {
+        self.label.clone().unwrap_or_else(|| self.network.to_string())
+    }
+
+    pub fn get_interval(&self, general: &General) -> u64 {
+        self.interval.unwrap_or(general.interval)
+    }
+
+    pub fn get_round(&self, general: &General) -> u8 {
+        self.round.unwrap_or(general.round)
+    }
+
+    pub fn get_scanner(&self, general: &General) -> Scanner {
+        self.scanner.unwrap_or(general.scanner)
+    }
+
+    pub fn get_lease_file(&self, general: &General) -> Option<String> {
+        self.lease_file.clone().or_else(|| general.lease_file.clone())
+    }
+}
+
+// Trades the accuracy of the scans for the network load
+#[derive(Clone, Deserialize, Debug)]
+pub struct Nmap {
+    #[serde(default = "get_probes")]
+    pub probes: Vec<Probe>,
+
+    // nmap uses its own default ports if empty
+    #[serde(default)]
+    pub syn_ports: Vec<u16>,
+
+    #[serde(default)]
+    pub ack_ports: Vec<u16>,
+
+    // Timing template from 0 (paranoid) to 5 (insane), '-T'
+    #[serde(default)]
+    pub timing: Option<u8>,
+
+    #[serde(default)]
+    pub max_retries: Option<u8>,
+
+    // In nmap time format, i.e. '500ms', '30s', '1m'
+    #[serde(default)]
+    pub host_timeout: Option<String>,
+}
+
+impl Nmap {
+    pub fn is_valid(&self) -> bool {
+        if self.probes.is_empty() {
+            return false;
+        }
+        if self.syn_ports.iter().chain(self.ack_ports.iter()).any(|p| *p == 0) {
+            return false;
+        }
+        if let Some(t) = self.timing {
+            if t > 5 {
+                return false;
+            }
+        }
+        if let Some(ref t) = self.host_timeout {
+            let digits = t.trim_end_matches(|c: char| c.is_alphabetic());
+            let unit = &t[digits.len()..];
+            if digits.is_empty() || digits.parse::<u64>().is_err() || !["", "ms", "s", "m", "h"].contains(&unit) {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Arguments passed to nmap in addition to the ping scan
+    pub fn get_args(&self) -> Vec<String> {
+        let mut args: Vec<String> = vec![];
+
+        let ports = |p: &Vec<u16>| p.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(",");
+
+        for probe in &self.probes {
+            match probe {
+                Probe::Arp => args.push("-PR".to_string()),
+                Probe::Icmp => args.push("-PE".to_string()),
+                Probe::Syn => args.push(format!("-PS{}", ports(&self.syn_ports))),
+                Probe::Ack => args.push(format!("-PA{}", ports(&self.ack_ports))),
+            }
+        }
+
+        if let Some(t) = self.timing {
+            args.push(format!("-T{}", t));
+        }
+
+        if let Some(r) = self.max_retries {
+            args.push("--max-retries".to_string());
+            args.push(r.to_string());
+        }
+
+        if let Some(ref t) = self.host_timeout {
+            args.push("--host-timeout".to_string());
+            args.push(t.to_string());
+        }
+
+        args
+    }
+}
+
+impl Default for Nmap {
+    fn default() -> Nmap {
+        Nmap {
+            probes: get_probes(),
+            syn_ports: vec![],
+            ack_ports: vec![],
+            timing: None,
+            max_retries: None,
+            host_timeout: None,
+        }
+    }
+}
+
+// Listens the ARP, DHCP and mDNS traffic to detect the arrivals instantly
+#[derive(Clone, Deserialize, Debug, Default)]
+pub struct Passive {
+    #[serde(default)]
+    pub enabled: bool,
+}
+
+// The presence is saved after every scan and restored on the next start
+#[derive(Clone, Deserialize, Debug)]
+pub struct Presence {
+    #[serde(default = "get_report")]
+    pub policy: RestorePolicy,
+
+    // The saved state is ignored if it is older than this (ms), 0 to never ignore
+    #[serde(default = "get_max_age")]
+    pub max_age: u64,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct Security {
+    #[serde(default = "get_monitor")]
+    pub mode: SecurityMode,
+
+    #[serde(default = "get_repeat_interval")]
+    pub repeat_interval: u64,
+
+    #[serde(default = "get_critical")]
+    pub severity: Severity,
+
+    #[serde(default = "get_true")]
+    pub arp_watch: bool,
+
+    #[serde(default = "get_max_ips_per_mac")]
+    pub max_ips_per_mac: usize,
+
+    // Scans in a row the new gateway MAC must be seen to be accepted, 0 to wait for the ack
+    #[serde(default = "get_gateway_relearn")]
+    pub gateway_relearn: usize,
+}
+
+// A host is identified by its MAC, or by its hostname / DHCP client-id
+// for the devices those use randomized MAC addresses
 #[derive(Clone, Deserialize, Debug)]
 pub struct Host {
-    mac: String,
+    #[serde(default)]
+    mac: Option<String>,
+    #[serde(default)]
+    hostname: Option<String>,
+    #[serde(default)]
+    client_id: Option<String>,
+    name: String,
+    device: String,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+// A person is home if any of the primary devices is present
+#[derive(Clone, Deserialize, Debug)]
+pub struct Person {
     name: String,
+    devices: Vec<PersonDevice>,
+}
+
+// Identified like a host, the name is taken from the person
+#[derive(Clone, Deserialize, Debug)]
+pub struct PersonDevice {
+    #[serde(default)]
+    mac: Option<String>,
+    #[serde(default)]
+    hostname: Option<String>,
+    #[serde(default)]
+    client_id: Option<String>,
     device: String,
+    // i.e. a phone, not a desktop those stays at home
+    #[serde(default = "get_true")]
+    primary: bool,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+// Overrides the rules for the hosts those have the tag, i.e. 'iot', 'kids'
+// The first tag of the host those group sets a rule wins
+#[derive(Clone, Deserialize, Debug, Default)]
+pub struct Group {
+    #[serde(default)]
+    pub notify_on_connect: Option<bool>,
+
+    #[serde(default)]
+    pub notify_on_disconnect: Option<bool>,
+
+    // Alerts raised for the members are stored either way
+    #[serde(default)]
+    pub notify_alerts: Option<bool>,
+
+    #[serde(default)]
+    pub severity: Option<Severity>,
 }
 
 #[derive(Debug, Clone)]
 pub struct HostInfo {
     pub name: String,
     pub device_name: String,
+    // Owner of the device, if it is one of the 'people'
+    pub person: Option<String>,
+    pub primary: bool,
+    pub tags: Vec<String>,
 }
 
-impl Config {
-    fn is_valid(&self) -> bool {
-        for host in &self.hosts {
-            match MacAddr::from_str(host.mac.as_str()) {
-                Ok(r) => r,
-                Err(_) => return false,
-            };
+fn get_true() -> bool { true }
+
+fn get_critical() -> Severity { Severity::Critical }
+
+fn get_mdns_timeout() -> u64 { 500 }
+
+fn get_resolve_ttl() -> u64 { 3600000 }
+
+fn get_nmap() -> Scanner { Scanner::Nmap }
+
+fn get_probes() -> Vec<Probe> { vec![Probe::Syn] }
+
+fn get_notify_template() -> String { "Name: {name}\nDevice: {device}".to_string() }
+
+fn get_monitor() -> SecurityMode { SecurityMode::Monitor }
+
+fn get_repeat_interval() -> u64 { 300000 }
+
+fn get_max_ips_per_mac() -> usize { 1 }
+
+fn get_gateway_relearn() -> usize { 10 }
+
+fn get_report() -> RestorePolicy { RestorePolicy::Report }
+
+fn get_max_age() -> u64 { 86400000 }
+
+impl Default for Presence {
+    fn default() -> Presence {
+        Presence {
+            policy: get_report(),
+            max_age: get_max_age(),
         }
-        true
     }
 }
 
-pub fn get_config(filename: &str) -> Result<Config, ErrorType> {
+impl Default for Security {
+    fn default() -> Security {
+        Security {
+            mode: get_monitor(),
+            repeat_interval: get_repeat_interval(),
+            severity: get_critical(),
+            arp_watch: get_true(),
+            max_ips_per_mac: get_max_ips_per_mac(),
+            gateway_relearn: get_gateway_relearn(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HostMap {
+    pub macs: HashMap<MacAddr, HostInfo>,
+    pub hostnames: HashMap<String, HostInfo>,
+    pub client_ids: HashMap<String, HostInfo>,
+}
+
+impl Config {
+    // Notifications can not be shown to the root, they are only printed in dry-run
+    pub fn can_notify(&self) -> bool {
+        self.dry_run || !self.is_root
+    }
+
+    fn get_rule<T, F>(&self, tags: &Vec<String>, rule: F) -> Option<T>
+    where
+        F: Fn(&Group) -> Option<T>,
+    {
+        tags.iter().filter_map(|t| self.groups.get(t)).filter_map(rule).next()
+    }
+
+    pub fn notify_on_connect(&self, tags: &Vec<String>) -> bool {
+        self.get_rule(tags, |g| g.notify_on_connect).unwrap_or(self.general.notify_on_connect)
+    }
+
+    pub fn notify_on_disconnect(&self, tags: &Vec<String>) -> bool {
+        self.get_rule(tags, |g| g.notify_on_disconnect).unwrap_or(self.general.notify_on_disconnect)
+    }
+
+    pub fn notify_alerts(&self, tags: &Vec<String>) -> bool {
+        self.get_rule(tags, |g| g.notify_alerts).unwrap_or(true)
+    }
+
+    pub fn get_severity(&self, tags: &Vec<String>) -> Severity {
+        self.get_rule(tags, |g| g.severity).unwrap_or(self.security.severity)
+    }
+}
+
+// The config is checked by the same rules as 'config check', the problems are logged
+pub fn get_config(filename: &str) -> Result<Config, Error> {
     let mut file = OpenOptions::new()
         .read(true)
         .write(false)
         .open(&filename)
-        .unwrap();
+        .map_err(ErrorType::from)?;
     let mut content = String::new();
-    file.read_to_string(&mut content)?;
-    let config: Config = serde_yaml::from_str(&content).unwrap();
-    if config.is_valid() {
-        Ok(config)
-    } else {
-        Err(ErrorType::ParseError)
+    file.read_to_string(&mut content).map_err(ErrorType::from)?;
+    let problems = check::check_config(&content);
+    if !problems.is_empty() {
+        for p in &problems {
+            error!("[config::get_config()]: {}:{}:{}: {}", filename, p.position.line, p.position.col, p.message);
+        }
+        return Err(Error::ConfigProblems(problems.len()));
+    }
+    let config: Config = serde_yaml::from_str(&content).map_err(ErrorType::from)?;
+    Ok(config)
+}
+
+// Only the first label is compared, so 'iPhone' matches with 'iphone.lan'
+pub fn normalize_hostname(hostname: &str) -> String {
+    hostname.split('.').next().unwrap_or("").trim().to_lowercase()
+}
+
+impl HostMap {
+    // The MAC is validated by the config
+    fn insert(&mut self, mac: Option<String>, hostname: Option<String>, client_id: Option<String>, info: HostInfo) {
+        if let Some(mac) = mac {
+            self.macs.insert(MacAddr::from_str(&mac).unwrap(), info.clone());
+        }
+        if let Some(hostname) = hostname {
+            self.hostnames.insert(normalize_hostname(&hostname), info.clone());
+        }
+        if let Some(client_id) = client_id {
+            self.client_ids.insert(client_id.to_lowercase(), info);
+        }
+    }
+
+    pub fn find(&self, mac: &MacAddr, hostname: Option<&str>, client_id: Option<&str>) -> Option<&HostInfo> {
+        if let Some(info) = self.macs.get(mac) {
+            return Some(info);
+        }
+        if let Some(info) = client_id.and_then(|c| self.client_ids.get(&c.to_lowercase())) {
+            return Some(info);
+        }
+        hostname.and_then(|h| self.hostnames.get(&normalize_hostname(h)))
     }
 }
 
-pub fn get_mac_info_map(config: Config) -> HashMap<MacAddr, HostInfo> {
-    let mut map: HashMap<MacAddr, HostInfo> = HashMap::new();
+pub fn get_host_map(config: Config) -> HostMap {
+    let mut map = HostMap::default();
 
     for host in config.hosts {
-        let mac: MacAddr = MacAddr::from_str(&host.mac).unwrap();
+        let info = HostInfo {
+            name: host.name,
+            device_name: host.device,
+            person: None,
+            primary: false,
+            tags: host.tags,
+        };
+
+        map.insert(host.mac, host.hostname, host.client_id, info);
+    }
+
+    for person in config.people {
+        for device in person.devices {
+            let info = HostInfo {
+                name: person.name.to_string(),
+                device_name: device.device,
+                person: Some(person.name.to_string()),
+                primary: device.primary,
+                tags: device.tags,
+            };
 
-        map.insert(
-            mac,
-            HostInfo {
-                name: host.name,
-                device_name: host.device,
-            },
-        );
+            map.insert(device.mac, device.hostname, device.client_id, info);
+        }
     }
 
     map
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{env, fs, process};
+
+    const GENERAL: &str = "general:\n  interval: 1000\n  round: 1\n  notify_on_connect: true\n  notify_on_disconnect: true\n";
+
+    fn load(name: &str, content: &str) -> Result<Config, Error> {
+        let path = env::temp_dir().join(format!("netlyser-{}-{}.yaml", name, process::id()));
+        fs::write(&path, content).unwrap();
+        let config = get_config(&path.to_string_lossy());
+        let _ = fs::remove_file(&path);
+        config
+    }
+
+    fn is_rejected(config: Result<Config, Error>) -> bool {
+        match config {
+            Err(Error::ConfigProblems(count)) => count > 0,
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => false,
+        }
+    }
+
+    #[test]
+    fn get_config_loads_every_field() {
+        assert!(get_config("data/fixtures/config/full.yaml").is_ok());
+    }
+
+    #[test]
+    fn get_config_rejects_what_the_check_rejects() {
+        let zero = GENERAL.replace("interval: 1000", "interval: 0");
+        assert!(is_rejected(load("zero-interval", &format!("{}hosts: []\n", zero))));
+
+        let duplicate = format!("{}hosts:\n  - mac: \"01:23:45:67:89:AB\"\n    name: a\n    device: b\n  - mac: \"01:23:45:67:89:ab\"\n    name: c\n    device: d\n", GENERAL);
+        assert!(is_rejected(load("duplicate-mac", &duplicate)));
+
+        let unknown = format!("{}  colour: red\nhosts: []\n", GENERAL);
+        assert!(is_rejected(load("unknown-key", &unknown)));
+
+        let twice = format!("{}  round: 2\nhosts: []\n", GENERAL);
+        assert!(is_rejected(load("duplicate-key", &twice)));
+    }
+}
@@ -12,13 +12,18 @@ use std::{
 
 use pnet::util::{MacAddr};
 
+use crate::monitor::Event;
 use crate::net;
+use crate::oui;
+use crate::resolve;
+use crate::security;
+use crate::sniff;
 use crate::config;
 
 use rusqlite::types::ToSql;
-use rusqlite::{Connection, Result, NO_PARAMS};
+use rusqlite::{Connection, OptionalExtension, Result, Row, NO_PARAMS};
 
-use notify_rust::Notification;
+use notify_rust::{Notification, NotificationHint, NotificationUrgency};
 
 use chrono::prelude::*;
 
@@ -29,12 +34,105 @@ pub struct NotifyInfo {
     pub disconnected: bool,
 }
 
-#[derive(PartialEq, Debug, Clone)]
+#[derive(Debug, Clone)]
+pub struct Alert {
+    pub id: i64,
+    pub kind: String,
+    pub severity: String,
+    pub ip: String,
+    pub mac: String,
+    pub message: String,
+    pub time: i64,
+    pub last_notified: i64,
+    pub count: i64,
+    // Tags of the device when the alert is raised
+    pub tags: Vec<String>,
+}
+
+// Where a field of a host is taken from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    Nmap,
+    Lease,
+    Arp,
+    Oui,
+    Resolver,
+    Dhcp,
+    Mdns,
+}
+
+impl Source {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Source::Nmap => "nmap",
+            Source::Lease => "lease",
+            Source::Arp => "arp",
+            Source::Oui => "oui",
+            Source::Resolver => "resolver",
+            Source::Dhcp => "dhcp",
+            Source::Mdns => "mdns",
+        }
+    }
+}
+
+impl From<sniff::Source> for Source {
+    fn from(source: sniff::Source) -> Self {
+        match source {
+            sniff::Source::Arp => Source::Arp,
+            sniff::Source::Dhcp => Source::Dhcp,
+            sniff::Source::Mdns => Source::Mdns,
+            sniff::Source::Lease => Source::Lease,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Sources {
+    pub mac: Option<Source>,
+    pub hostname: Option<Source>,
+    pub vendor: Option<Source>,
+}
+
+#[derive(Debug, Clone)]
 pub struct Host {
     pub ip: Ipv4Addr,
     pub mac: MacAddr,
     pub name: String,
     pub device_name: String,
+    pub known: bool,
+    pub random: bool,
+    pub hostname: Option<String>,
+    pub client_id: Option<String>,
+    pub vendor: Option<String>,
+    // Label of the network the host is seen on
+    pub network: Option<String>,
+    pub sources: Sources,
+    // Owner of the device, see 'config::Person'
+    pub person: Option<String>,
+    pub primary: bool,
+    // Groups of the host, see 'config::Group'
+    pub tags: Vec<String>,
+}
+
+// A person those primary devices are present
+#[derive(Debug, Clone)]
+pub struct Person {
+    pub name: String,
+    pub devices: Vec<Host>,
+}
+
+impl Person {
+    pub fn get_devices(&self) -> String {
+        self.devices.iter().map(|d| d.device_name.to_string()).collect::<Vec<String>>().join(", ")
+    }
+}
+
+// Hostnames (so the names matched by them) are resolved best-effort and may
+// differ between the scanners, so they must not change the identity of a host
+impl PartialEq for Host {
+    fn eq(&self, other: &Host) -> bool {
+        self.ip == other.ip && self.mac == other.mac
+    }
 }
 
 impl Host {
@@ -44,6 +142,16 @@ impl Host {
             mac: MacAddr::zero(),
             name: String::new(),
             device_name: String::new(),
+            known: false,
+            random: false,
+            hostname: None,
+            client_id: None,
+            vendor: None,
+            network: None,
+            sources: Sources::default(),
+            person: None,
+            primary: false,
+            tags: vec![],
         }
     }
 
@@ -53,6 +161,7 @@ impl Host {
 
     pub fn set_mac(&mut self, mac: MacAddr) {
         self.mac = mac;
+        self.random = net::is_locally_administered(&mac);
     }
 
     pub fn set_info(&mut self, info: &config::HostInfo) {
@@ -66,6 +175,10 @@ impl Host {
         } else {
             self.device_name = info.device_name.to_string();
         }
+        self.person = info.person.clone();
+        self.primary = info.primary;
+        self.tags = info.tags.clone();
+        self.known = true;
     }
 
     pub fn set_name(&mut self, name: String) {
@@ -75,14 +188,48 @@ impl Host {
     pub fn set_device_name(&mut self, name: String) {
         self.device_name = name;
     }
+
+    // Name of the host, unknown ones are labelled with their hostname if any
+    // i.e. 'Unknown (Living-Room-TV.local)'
+    pub fn get_label(&self) -> String {
+        match self.hostname {
+            Some(ref hostname) if !self.known => format!("{} ({})", self.name, hostname),
+            _ => self.name.to_string(),
+        }
+    }
+
+    // Placeholders: {name}, {device}, {ip}, {mac}, {vendor}, {hostname}, {network}, {tags}
+    pub fn render(&self, template: &str) -> String {
+        template
+            .replace("{name}", &self.get_label())
+            .replace("{device}", &self.device_name)
+            .replace("{ip}", &self.ip.to_string())
+            .replace("{mac}", &self.mac.to_string())
+            .replace("{vendor}", self.vendor.as_ref().map(|x| x.as_str()).unwrap_or("Unknown"))
+            .replace("{hostname}", self.hostname.as_ref().map(|x| x.as_str()).unwrap_or(""))
+            .replace("{network}", self.network.as_ref().map(|x| x.as_str()).unwrap_or(""))
+            .replace("{tags}", &self.tags.join(", "))
+    }
 }
 
-pub fn get_notifies(olds: &Vec<Host>, news: &Vec<Host>, conf: &config::Config, db: &String) {
+// Changes between the scans, only the device inventory is updated to find the unknown ones
+// time: Unix timestamp of the scan, the recording time when replaying
+pub fn get_changes(olds: &Vec<Host>, news: &Vec<Host>, conf: &config::Config, conn: &Connection, time: i64) -> Vec<Event> {
     let mut rmvs: Vec<Host> = vec![];
     let mut adds: Vec<Host> = vec![];
 
     let mut change: bool = false;
 
+    let unknowns: Vec<Host> = match add_devices_to_db(conn, &news, time) {
+        Ok(v) => v.into_iter()
+            .filter(|h| !h.known && !(h.random && conf.general.ignore_random_unknown))
+            .collect(),
+        Err(e) => {
+            warn!("[db::get_changes()]: error throwed when running 'add_devices_to_db()' function. Err: {}, ", e);
+            vec![]
+        }
+    };
+
     for old in olds.clone() {
         if !news.contains(&old) {
             rmvs.push(old);
@@ -97,73 +244,143 @@ pub fn get_notifies(olds: &Vec<Host>, news: &Vec<Host>, conf: &config::Config, d
         }
     }
 
-    if change && (olds.len() != news.len()) {
-        if rmvs.len() > 0 {
-            on_hosts_disconnected(&db, rmvs, &conf);
-        }
-        if adds.len() > 0 {
-            on_hosts_connected(&db, adds, &conf);
-        }
+    let mut events: Vec<Event> = vec![];
+
+    if change {
+        events.extend(rmvs.into_iter().map(Event::Disconnected));
+        events.extend(adds.into_iter().map(Event::Connected));
     }
 
+    events.extend(unknowns.into_iter().map(Event::Unknown));
+
+    events
 }
 
-pub fn on_hosts_connected(db: &String, hosts: Vec<Host>, conf: &config::Config) {
-    for h in hosts {
-        if !conf.is_root && conf.general.notify_on_connect {
-            notify(&h, true);
-        }
-        match add_to_db(&db, &h, true) {
-            Ok(v) => {
-                info!("[db::on_hosts_connected()]: 'add_to_db()' success: {:?}", v);
-            }
-            Err(e) => {
-                warn!("[db::on_hosts_connected()]: error throwed when running 'add_to_db()' function. Err: {}, ", e);
-            }
-        }
+pub fn notify_event(event: &Event, conf: &config::Config) {
+    if !conf.can_notify() {
+        return;
+    }
+
+    match event {
+        Event::Connected(h) if conf.notify_on_connect(&h.tags) => notify(h, true, &conf.general.notify_template, conf.dry_run),
+        Event::Disconnected(h) if conf.notify_on_disconnect(&h.tags) => notify(h, false, &conf.general.notify_template, conf.dry_run),
+        Event::Unknown(h) if conf.general.notify_on_unknown => notify_unknown(h, conf.general.unknown_severity, conf.dry_run),
+        Event::Alert(a) if conf.notify_alerts(&a.tags) => security::notify_alert(a, conf.dry_run),
+        Event::Arrived(p) if conf.general.notify_on_arrive => notify_person(p, true, conf.dry_run),
+        Event::Departed(p) if conf.general.notify_on_leave => notify_person(p, false, conf.dry_run),
+        _ => {}
     }
 }
 
-pub fn on_hosts_disconnected(db: &String, hosts: Vec<Host>, conf: &config::Config) {
-    for h in hosts {
-        if !conf.is_root && conf.general.notify_on_disconnect {
-            notify(&h, false);
-        }
-        match add_to_db(&db, &h, false) {
-            Ok(v) => {
-                info!("[db::on_hosts_disconnected()]: 'add_to_db()' success: {:?}", v);
-            }
-            Err(e) => {
-                warn!("[db::on_hosts_disconnected()]: error throwed when running 'add_to_db()' function. Err: {}, ", e);
-            }
-        }
+pub fn store_event(conn: &Connection, event: &Event, time: i64) {
+    let res = match event {
+        Event::Connected(h) => add_to_db(conn, h, true, time),
+        Event::Disconnected(h) => add_to_db(conn, h, false, time),
+        // Hosts those MAC addresses are seen for the first time and are not in the config
+        Event::Unknown(h) => add_log_to_db(conn, h, "unknown", time),
+        // Stored when they are raised
+        Event::Alert(_) => Ok(()),
+        Event::Arrived(p) => open_session(conn, &p.name, time),
+        Event::Departed(p) => close_session(conn, &p.name, time),
+    };
+
+    if let Err(e) = res {
+        warn!("[db::store_event()]: error throwed when storing {:?}. Err: {}, ", event, e);
     }
 }
 
-pub fn notify(host: &Host, con_or_dis: bool){
-    let not: String = format!("Name: {}\nDevice: {}", host.name, host.device_name);
+pub fn notify_unknown(host: &Host, severity: config::Severity, dry_run: bool) {
+    let mut not: String = format!("IP: {}\nMAC: {}", host.ip, host.mac);
+    if host.random {
+        not.push_str(" (private)");
+    }
+    if let Some(ref hostname) = host.hostname {
+        not.push_str(&format!("\nHostname: {}", hostname));
+    }
+    if let Some(ref vendor) = host.vendor {
+        not.push_str(&format!("\nVendor: {}", vendor));
+    }
+    if dry_run {
+        print_notify("UNKNOWN DEVICE!", &not);
+        return;
+    }
+    let urgency = match severity {
+        config::Severity::Low => NotificationUrgency::Low,
+        config::Severity::Normal => NotificationUrgency::Normal,
+        config::Severity::Critical => NotificationUrgency::Critical,
+    };
+    show_notify(Notification::new()
+        .appname("Netlyser")
+        .summary("UNKNOWN DEVICE!")
+        .body(&not)
+        .hint(NotificationHint::Category("x-netlyser.unknown".to_string()))
+        .urgency(urgency)
+        .timeout(5000));
+}
+
+pub fn notify(host: &Host, con_or_dis: bool, template: &str, dry_run: bool){
+    let not: String = host.render(template);
+    if dry_run {
+        print_notify(if con_or_dis { "CONNECT!" } else { "DISCONNECT!" }, &not);
+        return;
+    }
     if con_or_dis {
-        Notification::new()
+        show_notify(Notification::new()
             .appname("Netlyser")
             .summary("CONNECT!")
             .body(&not)
-            .timeout(5000)
-            .show().unwrap();
+            .timeout(5000));
     } else {
-        Notification::new()
+        show_notify(Notification::new()
             .appname("Netlyser")
             .summary("DISCONNECT!")
             .body(&not)
-            .timeout(5000)
-            .show().unwrap();
+            .timeout(5000));
+    }
+}
+
+pub fn notify_person(person: &Person, arrived: bool, dry_run: bool) {
+    let summary = if arrived { "ARRIVED!" } else { "LEFT!" };
+    let not: String = format!("Name: {}\nDevices: {}", person.name, person.get_devices());
+    if dry_run {
+        print_notify(summary, &not);
+        return;
     }
+    show_notify(Notification::new()
+        .appname("Netlyser")
+        .summary(summary)
+        .body(&not)
+        .timeout(5000));
 }
 
-pub fn add_to_db(db: &String, host: &Host, con_or_dis: bool) -> Result<()>{
-    let conn = Connection::open(db.to_string())?;
-    let log_type = if con_or_dis { "connect".to_string() } else { "disconnect".to_string() };
+// There is no notification daemon without a D-Bus session (i.e. a headless box or a
+// service user), so the failure is logged instead of stopping the notifier
+pub fn show_notify(notification: &Notification) {
+    if let Err(e) = notification.show() {
+        warn!("[db::show_notify()]: unable to show the notification. Err: {}", e);
+    }
+}
+
+// Prints the notification instead of showing it in dry-run
+pub fn print_notify(summary: &str, body: &str) {
+    println!("{} {}", summary, body.replace('\n', ", "));
+}
 
-    let res = conn.execute(
+// Adds the column to the tables those are created by the older versions
+fn add_column(conn: &Connection, table: &str, column: &str, decl: &str) -> Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let names: Vec<String> = stmt.query_map(NO_PARAMS, |row| row.get(1))?.collect::<Result<_>>()?;
+
+    if !names.iter().any(|n| n == column) {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl), NO_PARAMS)?;
+        info!("[db::add_column()]: added column '{}' to '{}'", column, table);
+    }
+
+    Ok(())
+}
+
+fn create_tables(conn: &Connection) -> Result<()> {
+    conn.execute(
         "CREATE TABLE IF NOT EXISTS logs (
                    log_id           INTEGER PRIMARY KEY AUTOINCREMENT
                   ,log_name         TEXT NOT NULL
@@ -176,31 +393,507 @@ pub fn add_to_db(db: &String, host: &Host, con_or_dis: bool) -> Result<()>{
         NO_PARAMS,
     )?;
 
-    if res != 0 {
-        warn!("[db::add_to_db()]: Unable to add to db. Code: {}, ", res);
-    }
+    add_column(conn, "logs", "log_hostname", "TEXT")?;
+    add_column(conn, "logs", "log_network", "TEXT")?;
+    add_column(conn, "logs", "log_tags", "TEXT")?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS devices (
+                   dev_mac          TEXT PRIMARY KEY
+                  ,dev_ip           TEXT NOT NULL
+                  ,dev_known        INTEGER NOT NULL
+                  ,dev_first_seen   INTEGER NOT NULL
+                  ,dev_last_seen    INTEGER NOT NULL
+                  )",
+        NO_PARAMS,
+    )?;
+
+    add_column(conn, "devices", "dev_random", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column(conn, "devices", "dev_hostname", "TEXT")?;
+    add_column(conn, "devices", "dev_vendor", "TEXT")?;
+    add_column(conn, "devices", "dev_network", "TEXT")?;
+    add_column(conn, "devices", "dev_mac_source", "TEXT")?;
+    add_column(conn, "devices", "dev_hostname_source", "TEXT")?;
+    add_column(conn, "devices", "dev_vendor_source", "TEXT")?;
+    add_column(conn, "devices", "dev_tags", "TEXT")?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS alerts (
+                   alert_id             INTEGER PRIMARY KEY AUTOINCREMENT
+                  ,alert_type           TEXT NOT NULL
+                  ,alert_severity       TEXT NOT NULL
+                  ,alert_ip             TEXT NOT NULL
+                  ,alert_mac            TEXT NOT NULL
+                  ,alert_message        TEXT NOT NULL
+                  ,alert_time           INTEGER NOT NULL
+                  ,alert_last_notified  INTEGER NOT NULL
+                  ,alert_count          INTEGER NOT NULL
+                  ,alert_acked          INTEGER NOT NULL DEFAULT 0
+                  ,alert_acked_time     INTEGER
+                  )",
+        NO_PARAMS,
+    )?;
+
+    add_column(conn, "alerts", "alert_tags", "TEXT")?;
+    // An acknowledged alert keeps suppressing the same one until the device leaves
+    add_column(conn, "alerts", "alert_cleared", "INTEGER NOT NULL DEFAULT 0")?;
 
-    info!("[db::add_to_db()]: create function exited with: {:?}", res);
+    // Stays of the people, a session without an end is still going on
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS sessions (
+                   ses_id           INTEGER PRIMARY KEY AUTOINCREMENT
+                  ,ses_person       TEXT NOT NULL
+                  ,ses_start        INTEGER NOT NULL
+                  ,ses_end          INTEGER
+                  )",
+        NO_PARAMS,
+    )?;
+
+    // Hosts present in the last scan of every network and interface
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS presence (
+                   pres_network     TEXT NOT NULL
+                  ,pres_interface   TEXT NOT NULL
+                  ,pres_ip          TEXT NOT NULL
+                  ,pres_mac         TEXT NOT NULL
+                  ,pres_name        TEXT NOT NULL
+                  ,pres_device      TEXT NOT NULL
+                  ,pres_hostname    TEXT
+                  ,pres_known       INTEGER NOT NULL
+                  ,pres_random      INTEGER NOT NULL
+                  ,pres_time        INTEGER NOT NULL
+                  ,PRIMARY KEY (pres_network, pres_interface, pres_ip, pres_mac)
+                  )",
+        NO_PARAMS,
+    )?;
+
+    add_column(conn, "presence", "pres_person", "TEXT")?;
+    add_column(conn, "presence", "pres_primary", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column(conn, "presence", "pres_tags", "TEXT")?;
+
+    // A run without a stop time is still running or is killed
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS runs (
+                   run_id           INTEGER PRIMARY KEY AUTOINCREMENT
+                  ,run_start        INTEGER NOT NULL
+                  ,run_stop         INTEGER
+                  )",
+        NO_PARAMS,
+    )?;
+
+    Ok(())
+}
+
+// The tables must be created by 'init_db()' first
+pub fn open_db(db: &String) -> Result<Connection> {
+    Connection::open(db.to_string())
+}
+
+// Creates (or migrates) the tables once per process, so the monitor fails early on a bad path
+pub fn init_db(db: &String) -> Result<Connection> {
+    let conn = open_db(db)?;
+    create_tables(&conn)?;
+    Ok(conn)
+}
+
+pub fn add_to_db(conn: &Connection, host: &Host, con_or_dis: bool, time: i64) -> Result<()>{
+    let log_type = if con_or_dis { "connect" } else { "disconnect" };
+
+    add_log_to_db(conn, host, log_type, time)
+}
+
+pub fn add_log_to_db(conn: &Connection, host: &Host, log_type: &str, time: i64) -> Result<()>{
 
     let exec = conn.execute(
-        "INSERT INTO logs (log_name, log_device, log_ip, log_mac, log_type, log_time) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-        &[&host.name as &ToSql, &host.device_name as &ToSql, &host.ip.to_string() as &ToSql, &host.mac.to_string() as &ToSql, &log_type, &Local::now().timestamp() as &ToSql],
+        "INSERT INTO logs (log_name, log_device, log_ip, log_mac, log_type, log_time, log_hostname, log_network, log_tags) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        &[&host.get_label() as &ToSql, &host.device_name as &ToSql, &host.ip.to_string() as &ToSql, &host.mac.to_string() as &ToSql, &log_type, &time as &ToSql, &host.hostname as &ToSql, &host.network as &ToSql, &join_tags(&host.tags) as &ToSql],
     )?;
 
     if exec != 1 {
-        warn!("[db::add_to_db()]: Unable to execute command. Code: {}, ", exec);
+        warn!("[db::add_log_to_db()]: Unable to execute command. Code: {}, ", exec);
     }
 
-    info!("[db::add_to_db()]: execute function exited with: {:?}", exec);
+    info!("[db::add_log_to_db()]: execute function exited with: {:?}", exec);
 
     Ok(())
 }
 
-pub fn migrate_to_host_list(macmap: &HashMap<MacAddr, config::HostInfo>, gw: &net::Gateway, result: Vec<net::Host>, arps: HashMap<Ipv4Addr, MacAddr>, pc: Ipv4Addr) -> Vec<Host> {
+#[derive(Debug, Clone)]
+pub struct Log {
+    pub name: String,
+    pub device: String,
+    pub ip: String,
+    pub mac: String,
+    pub kind: String,
+    pub time: i64,
+    pub network: Option<String>,
+    pub tags: Vec<String>,
+}
+
+// The latest logs of the devices those have the tag, or all of them if None
+pub fn get_logs(conn: &Connection, tag: Option<&str>, limit: u32) -> Result<Vec<Log>> {
+
+    let mut stmt = conn.prepare(
+        "SELECT log_name, log_device, log_ip, log_mac, log_type, log_time, log_network, log_tags FROM logs WHERE ?1 IS NULL OR instr(',' || log_tags || ',', ?1) > 0 ORDER BY log_time DESC, log_id DESC LIMIT ?2",
+    )?;
+
+    let rows = stmt.query_map(&[&get_tag_pattern(tag) as &ToSql, &limit as &ToSql], |row| {
+        Ok(Log {
+            name: row.get(0)?,
+            device: row.get(1)?,
+            ip: row.get(2)?,
+            mac: row.get(3)?,
+            kind: row.get(4)?,
+            time: row.get(5)?,
+            network: row.get(6)?,
+            tags: split_tags(row.get(7)?),
+        })
+    })?;
+
+    rows.collect()
+}
+
+// Tags of the device in the inventory, the ones given to it by the config
+pub fn get_device_tags(conn: &Connection, mac: MacAddr) -> Result<Vec<String>> {
+
+    let tags: Option<Option<String>> = conn.query_row(
+        "SELECT dev_tags FROM devices WHERE dev_mac = ?1",
+        &[&mac.to_string() as &ToSql],
+        |row| row.get(0),
+    ).optional()?;
+
+    Ok(split_tags(tags.unwrap_or(None)))
+}
+
+// Tags are stored comma separated, NULL if there is none
+fn join_tags(tags: &Vec<String>) -> Option<String> {
+    if tags.is_empty() {
+        None
+    } else {
+        Some(tags.join(","))
+    }
+}
+
+fn split_tags(tags: Option<String>) -> Vec<String> {
+    tags.map(|t| t.split(',').map(String::from).collect()).unwrap_or_default()
+}
+
+// Searched in a tags column wrapped with commas, so a tag does not match the longer ones
+fn get_tag_pattern(tag: Option<&str>) -> Option<String> {
+    tag.map(|t| format!(",{},", t))
+}
+
+// Updates the device inventory and returns the hosts those are seen for the first time
+pub fn add_devices_to_db(conn: &Connection, hosts: &Vec<Host>, time: i64) -> Result<Vec<Host>> {
+    let now = time;
+
+    let mut firsts: Vec<Host> = vec![];
+
+    for host in hosts {
+        if host.mac == MacAddr::zero() {
+            continue;
+        }
+
+        let mac = host.mac.to_string();
+
+        let mac_source = host.sources.mac.map(|s| s.as_str());
+        let hostname_source = host.hostname.as_ref().and(host.sources.hostname).map(|s| s.as_str());
+        let vendor_source = host.vendor.as_ref().and(host.sources.vendor).map(|s| s.as_str());
+        let tags = join_tags(&host.tags);
+
+        let updated = conn.execute(
+            "UPDATE devices SET dev_ip = ?1, dev_known = ?2, dev_last_seen = ?3, dev_random = ?4, dev_hostname = COALESCE(?5, dev_hostname), dev_vendor = COALESCE(?6, dev_vendor), dev_network = COALESCE(?7, dev_network), dev_mac_source = COALESCE(?8, dev_mac_source), dev_hostname_source = COALESCE(?9, dev_hostname_source), dev_vendor_source = COALESCE(?10, dev_vendor_source), dev_tags = ?11 WHERE dev_mac = ?12",
+            &[&host.ip.to_string() as &ToSql, &host.known as &ToSql, &now as &ToSql, &host.random as &ToSql, &host.hostname as &ToSql, &host.vendor as &ToSql, &host.network as &ToSql, &mac_source as &ToSql, &hostname_source as &ToSql, &vendor_source as &ToSql, &tags as &ToSql, &mac as &ToSql],
+        )?;
+
+        if updated != 0 {
+            continue;
+        }
+
+        conn.execute(
+            "INSERT INTO devices (dev_mac, dev_ip, dev_known, dev_first_seen, dev_last_seen, dev_random, dev_hostname, dev_vendor, dev_network, dev_mac_source, dev_hostname_source, dev_vendor_source, dev_tags) VALUES (?1, ?2, ?3, ?4, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            &[&mac as &ToSql, &host.ip.to_string() as &ToSql, &host.known as &ToSql, &now as &ToSql, &host.random as &ToSql, &host.hostname as &ToSql, &host.vendor as &ToSql, &host.network as &ToSql, &mac_source as &ToSql, &hostname_source as &ToSql, &vendor_source as &ToSql, &tags as &ToSql],
+        )?;
+
+        info!("[db::add_devices_to_db()]: new device: {}", mac);
+
+        firsts.push(host.clone());
+    }
+
+    Ok(firsts)
+}
+
+// Starts a session unless the person has one going on, i.e. after a restart
+pub fn open_session(conn: &Connection, person: &str, time: i64) -> Result<()> {
+
+    let open: Option<i64> = conn.query_row(
+        "SELECT ses_id FROM sessions WHERE ses_person = ?1 AND ses_end IS NULL",
+        &[&person as &ToSql],
+        |row| row.get(0),
+    ).optional()?;
+
+    if open.is_none() {
+        conn.execute(
+            "INSERT INTO sessions (ses_person, ses_start) VALUES (?1, ?2)",
+            &[&person as &ToSql, &time as &ToSql],
+        )?;
+    }
+
+    Ok(())
+}
+
+pub fn close_session(conn: &Connection, person: &str, time: i64) -> Result<()> {
+
+    conn.execute(
+        "UPDATE sessions SET ses_end = ?1 WHERE ses_person = ?2 AND ses_end IS NULL",
+        &[&time as &ToSql, &person as &ToSql],
+    )?;
+
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct Run {
+    pub id: i64,
+    pub start: i64,
+    pub stop: Option<i64>,
+}
+
+pub fn get_last_run(conn: &Connection) -> Result<Option<Run>> {
+
+    conn.query_row(
+        "SELECT run_id, run_start, run_stop FROM runs ORDER BY run_id DESC LIMIT 1",
+        NO_PARAMS,
+        |row| Ok(Run {
+            id: row.get(0)?,
+            start: row.get(1)?,
+            stop: row.get(2)?,
+        }),
+    ).optional()
+}
+
+pub fn add_run(conn: &Connection) -> Result<i64> {
+
+    conn.execute(
+        "INSERT INTO runs (run_start) VALUES (?1)",
+        &[&Local::now().timestamp() as &ToSql],
+    )?;
+
+    Ok(conn.last_insert_rowid())
+}
+
+// Marks the run as stopped cleanly
+pub fn stop_run(conn: &Connection, id: i64) -> Result<()> {
+
+    conn.execute(
+        "UPDATE runs SET run_stop = ?1 WHERE run_id = ?2",
+        &[&Local::now().timestamp() as &ToSql, &id as &ToSql],
+    )?;
+
+    info!("[db::stop_run()]: run {} stopped", id);
+
+    Ok(())
+}
+
+// Replaces the saved presence of the network seen through the interface
+pub fn save_presence(conn: &mut Connection, network: &str, interface: &str, hosts: &Vec<Host>, time: i64) -> Result<()> {
+    let now = time;
+
+    let tx = conn.transaction()?;
+
+    tx.execute(
+        "DELETE FROM presence WHERE pres_network = ?1 AND pres_interface = ?2",
+        &[&network as &ToSql, &interface as &ToSql],
+    )?;
+
+    for host in hosts {
+        tx.execute(
+            "INSERT OR REPLACE INTO presence (pres_network, pres_interface, pres_ip, pres_mac, pres_name, pres_device, pres_hostname, pres_known, pres_random, pres_time, pres_person, pres_primary, pres_tags) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            &[&network as &ToSql, &interface as &ToSql, &host.ip.to_string() as &ToSql, &host.mac.to_string() as &ToSql, &host.name as &ToSql, &host.device_name as &ToSql, &host.hostname as &ToSql, &host.known as &ToSql, &host.random as &ToSql, &now as &ToSql, &host.person as &ToSql, &host.primary as &ToSql, &join_tags(&host.tags) as &ToSql],
+        )?;
+    }
+
+    tx.commit()
+}
+
+// Saved presence of the network seen through the interface, with the time it is saved
+pub fn get_presence(conn: &Connection, network: &str, interface: &str) -> Result<(Vec<Host>, i64)> {
+
+    let mut stmt = conn.prepare(
+        "SELECT pres_ip, pres_mac, pres_name, pres_device, pres_hostname, pres_known, pres_random, pres_time, pres_person, pres_primary, pres_tags FROM presence WHERE pres_network = ?1 AND pres_interface = ?2",
+    )?;
+
+    let rows = stmt.query_map(&[&network as &ToSql, &interface as &ToSql], |row| {
+        let ip: String = row.get(0)?;
+        let mac: String = row.get(1)?;
+
+        let mut h = Host::new();
+        h.set_ip(ip.parse().unwrap_or(Ipv4Addr::UNSPECIFIED));
+        h.mac = mac.parse().unwrap_or(MacAddr::zero());
+        h.name = row.get(2)?;
+        h.device_name = row.get(3)?;
+        h.hostname = row.get(4)?;
+        h.known = row.get(5)?;
+        h.random = row.get(6)?;
+        h.person = row.get(8)?;
+        h.primary = row.get(9)?;
+        h.tags = split_tags(row.get(10)?);
+        h.network = Some(network.to_string());
+
+        let time: i64 = row.get(7)?;
+        Ok((h, time))
+    })?;
+
+    let mut hosts: Vec<Host> = vec![];
+    let mut time: i64 = 0;
+
+    for row in rows {
+        let (h, t) = row?;
+        hosts.push(h);
+        time = time.max(t);
+    }
+
+    Ok((hosts, time))
+}
+
+const ALERT_COLUMNS: &str = "alert_id, alert_type, alert_severity, alert_ip, alert_mac, alert_message, alert_time, alert_last_notified, alert_count, alert_tags";
+
+fn get_alert(row: &Row) -> Result<Alert> {
+    Ok(Alert {
+        id: row.get(0)?,
+        kind: row.get(1)?,
+        severity: row.get(2)?,
+        ip: row.get(3)?,
+        mac: row.get(4)?,
+        message: row.get(5)?,
+        time: row.get(6)?,
+        last_notified: row.get(7)?,
+        count: row.get(8)?,
+        tags: split_tags(row.get(9)?),
+    })
+}
+
+// Raises a new alert unless there is one of the same type for the same MAC, those is
+// not acknowledged yet or acknowledged while the device is still present
+// Returns the alert only if it is newly raised
+pub fn raise_alert(conn: &Connection, kind: &str, severity: config::Severity, tags: &Vec<String>, ip: Ipv4Addr, mac: MacAddr, message: &str, time: i64) -> Result<Option<Alert>> {
+    let mac = mac.to_string();
+
+    let pending: Option<i64> = conn.query_row(
+        "SELECT alert_id FROM alerts WHERE alert_type = ?1 AND alert_mac = ?2 AND alert_cleared = 0",
+        &[&kind as &ToSql, &mac as &ToSql],
+        |row| row.get(0),
+    ).optional()?;
+
+    if pending.is_some() {
+        return Ok(None);
+    }
+
+    let now = time;
+
+    conn.execute(
+        "INSERT INTO alerts (alert_type, alert_severity, alert_ip, alert_mac, alert_message, alert_time, alert_last_notified, alert_count, alert_tags) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6, 1, ?7)",
+        &[&kind as &ToSql, &severity.as_str() as &ToSql, &ip.to_string() as &ToSql, &mac as &ToSql, &message as &ToSql, &now as &ToSql, &join_tags(tags) as &ToSql],
+    )?;
+
+    info!("[db::raise_alert()]: raised '{}' alert for {}", kind, mac);
+
+    Ok(Some(Alert {
+        id: conn.last_insert_rowid(),
+        kind: kind.to_string(),
+        severity: severity.as_str().to_string(),
+        ip: ip.to_string(),
+        mac: mac,
+        message: message.to_string(),
+        time: now,
+        last_notified: now,
+        count: 1,
+        tags: tags.clone(),
+    }))
+}
+
+// Pending alerts of the devices those have the tag, or all of them if None
+pub fn get_pending_alerts(conn: &Connection, tag: Option<&str>) -> Result<Vec<Alert>> {
+
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM alerts WHERE alert_acked = 0 AND (?1 IS NULL OR instr(',' || alert_tags || ',', ?1) > 0) ORDER BY alert_time", ALERT_COLUMNS))?;
+    let rows = stmt.query_map(&[&get_tag_pattern(tag) as &ToSql], get_alert)?;
+
+    rows.collect()
+}
+
+// Unacknowledged alerts those are not notified since 'interval' ms
+pub fn get_due_alerts(conn: &Connection, interval: u64, time: i64) -> Result<Vec<Alert>> {
+    let due = time - (interval / 1000) as i64;
+
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM alerts WHERE alert_acked = 0 AND alert_last_notified <= ?1", ALERT_COLUMNS))?;
+    let rows = stmt.query_map(&[&due as &ToSql], get_alert)?;
+
+    rows.collect()
+}
+
+pub fn set_alert_notified(conn: &Connection, id: i64, time: i64) -> Result<()> {
+
+    conn.execute(
+        "UPDATE alerts SET alert_last_notified = ?1, alert_count = alert_count + 1 WHERE alert_id = ?2",
+        &[&time as &ToSql, &id as &ToSql],
+    )?;
+
+    Ok(())
+}
+
+// Whether an alert of the type for the MAC is acknowledged and not re-armed yet
+pub fn is_alert_acked(conn: &Connection, kind: &str, mac: MacAddr) -> Result<bool> {
+
+    let acked: Option<i64> = conn.query_row(
+        "SELECT alert_id FROM alerts WHERE alert_type = ?1 AND alert_mac = ?2 AND alert_acked = 1 AND alert_cleared = 0",
+        &[&kind as &ToSql, &mac.to_string() as &ToSql],
+        |row| row.get(0),
+    ).optional()?;
+
+    Ok(acked.is_some())
+}
+
+// Lets the acknowledged alerts of the MAC be raised again, i.e. once the device left
+pub fn rearm_alerts(conn: &Connection, mac: MacAddr) -> Result<usize> {
+
+    conn.execute(
+        "UPDATE alerts SET alert_cleared = 1 WHERE alert_mac = ?1 AND alert_acked = 1 AND alert_cleared = 0",
+        &[&mac.to_string() as &ToSql],
+    )
+}
+
+// Acknowledges the pending alerts of the given MAC, or all of them if None
+pub fn ack_alerts(conn: &Connection, mac: Option<MacAddr>) -> Result<usize> {
+    let now = Local::now().timestamp();
+
+    match mac {
+        Some(m) => conn.execute(
+            "UPDATE alerts SET alert_acked = 1, alert_acked_time = ?1 WHERE alert_acked = 0 AND alert_mac = ?2",
+            &[&now as &ToSql, &m.to_string() as &ToSql],
+        ),
+        None => conn.execute(
+            "UPDATE alerts SET alert_acked = 1, alert_acked_time = ?1 WHERE alert_acked = 0",
+            &[&now as &ToSql],
+        ),
+    }
+}
+
+//scanner: Source of the results, nmap or the lease file
+pub fn migrate_to_host_list(hostmap: &config::HostMap, ouidb: &oui::OuiDb, resolver: &mut Option<resolve::Resolver>, gw: Option<&net::Gateway>, scanner: Source, result: Vec<net::Host>, arps: HashMap<Ipv4Addr, MacAddr>, pc: Ipv4Addr) -> Vec<Host> {
     let mut hosts: Vec<Host> = vec![];
 
     info!("[db::migrate_to_host_list()]: migrate len: {:?}", result.len());
 
+    // The hosts without a hostname are resolved together
+    if let Some(r) = resolver {
+        let ips: Vec<Ipv4Addr> = result.iter()
+            .filter(|h| h.get_hostname().is_none())
+            .filter_map(|h| h.get_ipv4())
+            .filter(|ip| *ip != pc && gw.map_or(true, |g| *ip != g.ip))
+            .collect();
+        r.prefetch(&ips);
+    }
+
     for host in result {
         let mut current: Option<Host> = Some(Host::new());
 
@@ -209,36 +902,169 @@ pub fn migrate_to_host_list(macmap: &HashMap<MacAddr, config::HostInfo>, gw: &ne
             None => continue,
         };
 
-        h.set_ip(host.address[0].addr.parse().unwrap());
+        match host.get_ipv4() {
+            Some(ip) => h.set_ip(ip),
+            None => continue,
+        }
 
         if h.ip.eq(&pc) {
             continue;
         }
 
-        if h.ip.eq(&gw.ip){
+        if gw.map_or(false, |g| h.ip == g.ip) {
             h.set_name("GATEWAY".to_string());
             h.set_device_name("GATEWAY".to_string());
+            h.known = true;
             hosts.push(h.clone());
             continue;
         }
 
-        match arps.get(&h.ip) {
-            Some(&mac) => {
-                h.set_mac(mac);
+        h.hostname = host.get_hostname();
+        h.sources.hostname = Some(scanner);
 
-                match macmap.get(&mac) {
-                    Some(n) => h.set_info(n),
-                    None => {
-                        h.set_name("Unknown".to_string());
-                        h.set_device_name("Unknown".to_string())
-                    }
+        if h.hostname.is_none() {
+            if let Some(r) = resolver {
+                h.hostname = r.resolve(&h.ip);
+                h.sources.hostname = Some(Source::Resolver);
+            }
+        }
+
+        h.client_id = host.client_id.clone();
+
+        // nmap reports the MAC only if it runs as root, the ARP cache may be stale
+        match host.get_mac() {
+            Some(mac) => {
+                h.set_mac(mac);
+                h.sources.mac = Some(scanner);
+            }
+            None => match arps.get(&h.ip) {
+                Some(mac) => {
+                    h.set_mac(*mac);
+                    h.sources.mac = Some(Source::Arp);
                 }
+                None => debug!("[db::migrate_to_host_list()]: no MAC found for {}", h.ip),
+            },
+        }
+
+        // nmap reports the vendor only if it runs as root
+        match ouidb.lookup(&h.mac) {
+            Some(v) => {
+                h.vendor = Some(v.to_string());
+                h.sources.vendor = Some(Source::Oui);
+            }
+            None => {
+                h.vendor = host.address.iter()
+                    .find(|a| a.addrtype == "mac" && a.vendor != "Unknown")
+                    .map(|a| a.vendor.to_string());
+                h.sources.vendor = Some(scanner);
             }
-            _ => println!("Can't find MAC for IP: {}", h.ip),
         }
 
+        set_identity(h, hostmap);
+
         hosts.push(h.clone());
     }
 
     hosts
 }
+
+fn set_identity(h: &mut Host, hostmap: &config::HostMap) {
+    match hostmap.find(&h.mac, h.hostname.as_ref().map(|x| x.as_str()), h.client_id.as_ref().map(|x| x.as_str())) {
+        Some(n) => h.set_info(n),
+        None => {
+            h.set_name("Unknown".to_string());
+            h.set_device_name("Unknown".to_string())
+        }
+    }
+}
+
+// Same as 'migrate_to_host_list()' for a host seen by the passive discovery
+pub fn migrate_sighting(hostmap: &config::HostMap, ouidb: &oui::OuiDb, gw: Option<&net::Gateway>, sighting: &sniff::Sighting, pc: Ipv4Addr) -> Option<Host> {
+    let mut h = Host::new();
+
+    h.set_ip(sighting.ip);
+
+    if h.ip.eq(&pc) {
+        return None;
+    }
+
+    if gw.map_or(false, |g| h.ip == g.ip) {
+        h.set_name("GATEWAY".to_string());
+        h.set_device_name("GATEWAY".to_string());
+        h.known = true;
+        return Some(h);
+    }
+
+    h.set_mac(sighting.mac);
+    h.hostname = sighting.hostname.clone();
+    h.client_id = sighting.client_id.clone();
+    h.vendor = ouidb.lookup(&h.mac).map(|v| v.to_string());
+
+    h.sources = Sources {
+        mac: Some(sighting.source.into()),
+        hostname: Some(sighting.source.into()),
+        vendor: Some(Source::Oui),
+    };
+
+    set_identity(&mut h, hostmap);
+
+    Some(h)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{env, fs, process, str::FromStr};
+
+    use crate::serde_yaml;
+
+    fn get_db(name: &str) -> Connection {
+        let path = env::temp_dir().join(format!("netlyser-{}-{}.db", name, process::id()));
+        let _ = fs::remove_file(&path);
+        init_db(&path.to_string_lossy().to_string()).unwrap()
+    }
+
+    fn get_config() -> config::Config {
+        let content = "general:\n  interval: 1000\n  round: 1\n  notify_on_connect: true\n  notify_on_disconnect: true\n  notify_on_unknown: false\nhosts: []\n";
+        serde_yaml::from_str(content).unwrap()
+    }
+
+    fn get_host(ip: &str, mac: &str, network: &str) -> Host {
+        let mut h = Host::new();
+        h.set_ip(ip.parse().unwrap());
+        h.set_mac(MacAddr::from_str(mac).unwrap());
+        h.network = Some(network.to_string());
+        h
+    }
+
+    fn get_presence(events: &Vec<Event>) -> Vec<(&'static str, String)> {
+        events.iter().filter_map(|e| match e {
+            Event::Connected(h) => Some(("connected", h.mac.to_string())),
+            Event::Disconnected(h) => Some(("disconnected", h.mac.to_string())),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn changes_with_the_same_host_count_are_reported() {
+        let db = get_db("changes");
+        let conf = get_config();
+
+        // A leave on one network and a join on another in the same pass
+        let home = get_host("192.168.1.20", "02:00:00:00:00:20", "home");
+        let iot = get_host("10.0.20.30", "02:00:00:00:00:30", "iot");
+        let tv = get_host("192.168.1.40", "02:00:00:00:00:40", "home");
+
+        let olds = vec![home.clone(), tv.clone()];
+        let news = vec![tv.clone(), iot.clone()];
+
+        let events = get_changes(&olds, &news, &conf, &db, 1);
+        assert_eq!(get_presence(&events), vec![
+            ("disconnected", "02:00:00:00:00:20".to_string()),
+            ("connected", "02:00:00:00:00:30".to_string()),
+        ]);
+
+        assert!(get_presence(&get_changes(&news, &news, &conf, &db, 2)).is_empty());
+    }
+}
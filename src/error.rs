@@ -6,15 +6,71 @@
 // ====================================================
 
 use crate::clap;
+use crate::config;
+use crate::net;
+use crate::rusqlite;
 use std::{error::Error as StdError, fmt, io, sync::PoisonError};
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
+pub enum ExitCodes {
+    RootRequired = 1,
+    ConfigFileDoesNotExist = 2,
+    ConfigInvalid = 3,
+    NmapNotInstalled = 4,
+    NmapRunError = 5,
+    ResultWriteError = 6,
+    RootCheckError = 7,
+    RootCommandError = 8,
+    HostnameNotFound = 9,
+    HostnameRunError = 10,
+    HostnameParseError = 11,
+    DBCreateError = 12,
+    NetworkNotFound = 13,
+    GatewayNotFound = 14,
+    InterfaceInvalid = 15,
+    IoError = 16,
+    InternalError = 17,
+}
+
 pub enum Error {
     Canonicalization(String, io::Error),
     Clap(clap::Error),
+    Config(config::ErrorType),
+    // Number of the problems reported by 'config check'
+    ConfigProblems(usize),
+    Db(rusqlite::Error),
+    // Interface name, reason
+    Interface(String, io::Error),
     Io(io::Error),
+    // No network to scan or no local address in the network
+    Network(String),
+    Nmap(net::NmapError),
     PoisonedLock,
+    RootCheck(String),
+}
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        let code = match self {
+            Error::Canonicalization(_, _) => ExitCodes::IoError,
+            // clap exits by itself, see 'clap::Error::exit()'
+            Error::Clap(_) => ExitCodes::InternalError,
+            Error::Config(config::ErrorType::ReadError) => ExitCodes::ConfigFileDoesNotExist,
+            Error::Config(_) => ExitCodes::ConfigInvalid,
+            Error::ConfigProblems(_) => ExitCodes::ConfigInvalid,
+            Error::Db(_) => ExitCodes::DBCreateError,
+            Error::Interface(_, _) => ExitCodes::InterfaceInvalid,
+            Error::Io(_) => ExitCodes::IoError,
+            Error::Network(_) => ExitCodes::NetworkNotFound,
+            Error::Nmap(net::NmapError::NotInstalled) => ExitCodes::NmapNotInstalled,
+            Error::Nmap(_) => ExitCodes::NmapRunError,
+            Error::PoisonedLock => ExitCodes::InternalError,
+            Error::RootCheck(_) => ExitCodes::RootCheckError,
+        };
+
+        code as i32
+    }
 }
 
 impl StdError for Error {
@@ -30,6 +86,18 @@ impl From<clap::Error> for Error {
     }
 }
 
+impl From<config::ErrorType> for Error {
+    fn from(err: config::ErrorType) -> Self {
+        Error::Config(err)
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        Error::Db(err)
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Self {
         Error::Io(err)
@@ -37,6 +105,12 @@ impl From<io::Error> for Error {
 }
 
 
+impl From<net::NmapError> for Error {
+    fn from(err: net::NmapError) -> Self {
+        Error::Nmap(err)
+    }
+}
+
 impl<'a, T> From<PoisonError<T>> for Error {
     fn from(_err: PoisonError<T>) -> Self {
         Error::PoisonedLock
@@ -50,8 +124,15 @@ impl fmt::Display for Error {
                 ("Path", format!("couldn't canonicalize '{}':\n{}", path, err))
             }
             Error::Clap(err) => ("Argument", err.to_string()),
+            Error::Config(err) => ("Config", err.to_string()),
+            Error::ConfigProblems(count) => ("Config", format!("{} problem(s) found", count)),
+            Error::Db(err) => ("Database", err.to_string()),
+            Error::Interface(name, err) => ("Interface", format!("'{}' is invalid: {}", name, err)),
             Error::Io(err) => ("I/O", err.to_string()),
+            Error::Network(err) => ("Network", err.to_string()),
+            Error::Nmap(err) => ("Nmap", err.to_string()),
             Error::PoisonedLock => ("Internal", "poisoned lock".to_string()),
+            Error::RootCheck(err) => ("Root check", err.to_string()),
         };
 
         write!(f, "{} error: {}", error_type, error)
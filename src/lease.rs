@@ -0,0 +1,366 @@
+// ====================================================
+// Netlyser Copyright(C) 2019 Furkan Türkal
+// This program comes with ABSOLUTELY NO WARRANTY; This is free software,
+// and you are welcome to redistribute it under certain conditions; See
+// file LICENSE, which is part of this source code package, for details.
+// ====================================================
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::Error,
+    net::Ipv4Addr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::Sender,
+        Arc,
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use pnet::util::MacAddr;
+
+use chrono::prelude::*;
+
+use crate::net;
+use crate::sniff;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lease {
+    pub ip: Ipv4Addr,
+    pub mac: MacAddr,
+    pub hostname: Option<String>,
+    pub client_id: Option<String>,
+    // Unix timestamp, None if the lease never expires
+    pub expires: Option<i64>,
+}
+
+impl Lease {
+    pub fn is_expired(&self, now: i64) -> bool {
+        match self.expires {
+            Some(e) => e <= now,
+            None => false,
+        }
+    }
+
+    pub fn to_sighting(&self) -> sniff::Sighting {
+        sniff::Sighting {
+            ip: self.ip,
+            mac: self.mac,
+            hostname: self.hostname.clone(),
+            client_id: self.client_id.clone(),
+            source: sniff::Source::Lease,
+        }
+    }
+
+    // Same structure as the nmap results, so they go through the same pipeline
+    pub fn to_host(&self) -> net::Host {
+        let mut addresses = vec![net::Address {
+            addr: self.ip.to_string(),
+            addrtype: "ipv4".to_string(),
+            vendor: "Unknown".to_string(),
+        }];
+        addresses.push(net::Address {
+            addr: self.mac.to_string(),
+            addrtype: "mac".to_string(),
+            vendor: "Unknown".to_string(),
+        });
+
+        net::Host {
+            status: net::Status {
+                state: "up".to_string(),
+                reason: "dhcp-lease".to_string(),
+                reason_ttl: "0".to_string(),
+            },
+            address: addresses,
+            hostnames: net::Hostnames {
+                hostname: self.hostname.iter().map(|h| net::Hostname {
+                    name: h.to_string(),
+                    kind: "lease".to_string(),
+                }).collect(),
+            },
+            client_id: self.client_id.clone(),
+        }
+    }
+}
+
+// Active leases of a dnsmasq or an ISC dhcpd lease file
+pub fn read_leases(path: &str) -> Result<Vec<Lease>, Error> {
+    let content = fs::read_to_string(path)?;
+    let now = Local::now().timestamp();
+
+    let leases = if content.contains("lease ") && content.contains('{') {
+        parse_dhcpd(&content)
+    } else {
+        parse_dnsmasq(&content)
+    };
+
+    Ok(leases.into_iter().filter(|l| !l.is_expired(now)).collect())
+}
+
+pub fn do_scan_leases(path: &str) -> Vec<net::Host> {
+    match read_leases(path) {
+        Ok(r) => r.iter().map(|l| l.to_host()).collect(),
+        Err(e) => {
+            warn!("[lease::do_scan_leases()]: unable to read the leases from {}. Err: {}", path, e);
+            vec![]
+        }
+    }
+}
+
+// <expiry> <mac> <ip> <hostname> <client-id>
+// '1557093046 01:23:45:67:89:ab 192.168.1.10 iPhone 01:01:23:45:67:89:ab'
+pub fn parse_dnsmasq(content: &str) -> Vec<Lease> {
+    let mut leases: Vec<Lease> = vec![];
+
+    for line in content.lines() {
+        let x: Vec<&str> = line.split_whitespace().collect();
+
+        if x.len() < 3 {
+            continue;
+        }
+
+        let (mac, ip) = match (MacAddr::from_str(x[1]), x[2].parse::<Ipv4Addr>()) {
+            (Ok(m), Ok(i)) => (m, i),
+            _ => continue,
+        };
+
+        let expires = match x[0].parse::<i64>() {
+            Ok(0) | Err(_) => None,
+            Ok(e) => Some(e),
+        };
+
+        let field = |i: usize| x.get(i).filter(|v| **v != "*").map(|v| v.to_string());
+
+        leases.push(Lease {
+            ip: ip,
+            mac: mac,
+            hostname: field(3),
+            client_id: field(4).map(|c| c.to_lowercase()),
+            expires: expires,
+        });
+    }
+
+    leases
+}
+
+// lease 192.168.1.10 {
+//   ends 3 2019/05/01 22:00:00;
+//   binding state active;
+//   hardware ethernet 01:23:45:67:89:ab;
+//   uid "\001\001#Eg\211\253";
+//   client-hostname "iPhone";
+// }
+pub fn parse_dhcpd(content: &str) -> Vec<Lease> {
+    // The file is append-only, so the last block of an IP is the current one
+    let mut leases: HashMap<Ipv4Addr, Lease> = HashMap::new();
+    let mut order: Vec<Ipv4Addr> = vec![];
+
+    let mut ip: Option<Ipv4Addr> = None;
+    let mut mac: Option<MacAddr> = None;
+    let mut hostname: Option<String> = None;
+    let mut client_id: Option<String> = None;
+    let mut expires: Option<i64> = None;
+    let mut active = true;
+
+    for line in content.lines() {
+        let line = line.trim().trim_end_matches(';');
+
+        if line.starts_with("lease ") && line.ends_with('{') {
+            ip = line[6..line.len() - 1].trim().parse().ok();
+            mac = None;
+            hostname = None;
+            client_id = None;
+            expires = None;
+            active = true;
+        } else if line == "}" {
+            if let (Some(i), Some(m)) = (ip, mac) {
+                if active {
+                    if !leases.contains_key(&i) {
+                        order.push(i);
+                    }
+                    leases.insert(i, Lease {
+                        ip: i,
+                        mac: m,
+                        hostname: hostname.clone(),
+                        client_id: client_id.clone(),
+                        expires: expires,
+                    });
+                } else {
+                    leases.remove(&i);
+                }
+            }
+            ip = None;
+        } else if line.starts_with("hardware ethernet ") {
+            mac = MacAddr::from_str(line[18..].trim()).ok();
+        } else if line.starts_with("client-hostname ") {
+            hostname = Some(line[16..].trim().trim_matches('"').to_string());
+        } else if line.starts_with("uid ") {
+            client_id = Some(parse_uid(line[4..].trim()));
+        } else if line.starts_with("binding state ") {
+            active = line[14..].trim() == "active";
+        } else if line.starts_with("ends ") {
+            // 'ends never' or 'ends <weekday> <yyyy/mm/dd> <hh:mm:ss>' in UTC
+            let x: Vec<&str> = line.split_whitespace().collect();
+            expires = if x.len() >= 4 {
+                NaiveDateTime::parse_from_str(&format!("{} {}", x[2], x[3]), "%Y/%m/%d %H:%M:%S")
+                    .ok()
+                    .map(|d| Utc.from_utc_datetime(&d).timestamp())
+            } else {
+                None
+            };
+        }
+    }
+
+    order.iter().filter_map(|i| leases.remove(i)).collect()
+}
+
+// uid is either a quoted string with octal escapes or colon separated hex,
+// both are returned as colon separated hex like the dnsmasq client-id
+fn parse_uid(uid: &str) -> String {
+    if !uid.starts_with('"') {
+        return uid.to_lowercase();
+    }
+
+    let mut bytes: Vec<u8> = vec![];
+    let mut chars = uid.trim_matches('"').chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            bytes.extend_from_slice(c.to_string().as_bytes());
+            continue;
+        }
+        let mut octal = String::new();
+        while octal.len() < 3 && chars.peek().map_or(false, |c| c.is_digit(8)) {
+            octal.push(chars.next().unwrap());
+        }
+        match u8::from_str_radix(&octal, 8) {
+            Ok(b) => bytes.push(b),
+            Err(_) => {
+                if let Some(e) = chars.next() {
+                    bytes.extend_from_slice(e.to_string().as_bytes());
+                }
+            }
+        }
+    }
+
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<String>>().join(":")
+}
+
+// Polls the lease file and sends the new or renewed leases to 'tx' until it
+// is stopped, a renewal means the device is alive
+pub fn watch(path: &str, tx: Sender<sniff::Sighting>, stop: Arc<AtomicBool>) -> thread::JoinHandle<()> {
+    let path = path.to_string();
+
+    thread::spawn(move || {
+        let mut modified: Option<SystemTime> = None;
+        let mut lasts: Option<Vec<Lease>> = None;
+
+        while !stop.load(Ordering::SeqCst) {
+            let m = fs::metadata(&path).and_then(|m| m.modified()).ok();
+
+            if m != modified {
+                modified = m;
+
+                match read_leases(&path) {
+                    Ok(leases) => {
+                        if let Some(ref olds) = lasts {
+                            for lease in leases.iter().filter(|l| !olds.contains(l)) {
+                                debug!("[lease::watch()]: {:?}", lease);
+                                if tx.send(lease.to_sighting()).is_err() {
+                                    return;
+                                }
+                            }
+                        }
+                        lasts = Some(leases);
+                    }
+                    Err(e) => warn!("[lease::watch()]: unable to read the leases from {}. Err: {}", path, e),
+                }
+            }
+
+            thread::sleep(Duration::from_secs(1));
+        }
+
+        info!("[lease::watch()]: stopped watching {}", path);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DNSMASQ: &str = "data/fixtures/leases/dnsmasq.leases";
+    const DHCPD: &str = "data/fixtures/leases/dhcpd.leases";
+
+    fn lease(ip: &str, mac: &str, hostname: Option<&str>, client_id: Option<&str>, expires: Option<i64>) -> Lease {
+        Lease {
+            ip: ip.parse().unwrap(),
+            mac: MacAddr::from_str(mac).unwrap(),
+            hostname: hostname.map(String::from),
+            client_id: client_id.map(String::from),
+            expires: expires,
+        }
+    }
+
+    #[test]
+    fn parse_dnsmasq_reads_every_lease() {
+        let leases = parse_dnsmasq(&fs::read_to_string(DNSMASQ).unwrap());
+
+        // The DUID, IPv6 and the malformed lines are skipped
+        assert_eq!(leases, vec![
+            lease("192.168.1.20", "01:23:45:67:89:ab", Some("Furkans-iPhone"), Some("01:01:23:45:67:89:ab"), Some(4102444800)),
+            lease("192.168.1.21", "02:00:00:00:00:21", None, Some("01:02:00:00:00:00:21"), Some(4102444800)),
+            lease("192.168.1.22", "b8:27:eb:00:00:22", Some("raspberrypi"), None, Some(4102444800)),
+            lease("192.168.1.23", "50:c7:bf:00:00:23", Some("static-printer"), None, None),
+            lease("192.168.1.24", "02:00:00:00:00:24", Some("expired-laptop"), Some("01:02:00:00:00:00:24"), Some(1557093046)),
+        ]);
+    }
+
+    #[test]
+    fn parse_dhcpd_keeps_the_last_block_of_an_ip() {
+        let leases = parse_dhcpd(&fs::read_to_string(DHCPD).unwrap());
+
+        // '.30' is renewed by another device, '.33' is freed
+        assert_eq!(leases, vec![
+            lease("192.168.1.30", "02:00:00:00:00:3a", Some("new-tablet"), None, Some(4102444800)),
+            lease("192.168.1.31", "01:23:45:67:89:ab", Some("Furkans-iPhone"), Some("01:01:23:45:67:89:ab"), None),
+            lease("192.168.1.32", "b8:27:eb:00:00:32", None, Some("01:b8:27:eb:00:00:32"), Some(4102444800)),
+            lease("192.168.1.34", "02:00:00:00:00:34", Some("expired-laptop"), None, Some(1557180000)),
+        ]);
+    }
+
+    #[test]
+    fn read_leases_skips_the_expired_ones() {
+        let ips = |path: &str| read_leases(path).unwrap().iter().map(|l| l.ip.to_string()).collect::<Vec<String>>();
+
+        assert_eq!(ips(DNSMASQ), vec!["192.168.1.20", "192.168.1.21", "192.168.1.22", "192.168.1.23"]);
+        assert_eq!(ips(DHCPD), vec!["192.168.1.30", "192.168.1.31", "192.168.1.32"]);
+    }
+
+    #[test]
+    fn to_host_keeps_the_identity() {
+        let l = lease("192.168.1.20", "01:23:45:67:89:ab", Some("Furkans-iPhone"), Some("01:01:23:45:67:89:ab"), None);
+        let host = l.to_host();
+
+        assert!(host.is_up());
+        assert_eq!(host.get_ipv4(), Some(l.ip));
+        assert_eq!(host.get_mac(), Some(l.mac));
+        assert_eq!(host.get_hostname(), l.hostname);
+        assert_eq!(host.client_id, l.client_id);
+    }
+
+    #[test]
+    fn watch_exits_once_stopped() {
+        let (tx, rx) = std::sync::mpsc::channel::<sniff::Sighting>();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let watcher = watch(DNSMASQ, tx, stop.clone());
+        stop.store(true, Ordering::SeqCst);
+        watcher.join().unwrap();
+
+        // The sender is dropped with the thread
+        assert!(rx.recv().is_err());
+    }
+}
@@ -21,19 +21,32 @@ extern crate rusqlite;
 extern crate serde;
 extern crate serde_yaml;
 extern crate serde_xml_rs;
+extern crate yaml_rust;
 
-extern crate tempfile;
 extern crate chrono;
 extern crate pnet;
 extern crate notify_rust;
 
 extern crate regex;
 
+extern crate libc;
+
+pub mod check;
 pub mod cli;
 pub mod error;
 pub mod run;
 pub mod config;
 pub mod db;
+pub mod lease;
+pub mod monitor;
 pub mod net;
-
+pub mod oui;
+pub mod people;
+pub mod replay;
+pub mod resolve;
+pub mod security;
+pub mod signal;
+pub mod sniff;
+
+pub use crate::monitor::{Event, Monitor};
 pub use crate::run::run;
@@ -9,6 +9,14 @@ extern crate netlyser;
 
 use netlyser::{cli, error, run};
 
-fn main() -> error::Result<()> {
-    run(cli::get_args()?)
+use std::process::exit;
+
+fn main() {
+    if let Err(e) = cli::get_args().and_then(run) {
+        if let error::Error::Clap(ref err) = e {
+            err.exit();
+        }
+        eprintln!("{}", e);
+        exit(e.exit_code());
+    }
 }
@@ -0,0 +1,1100 @@
+// ====================================================
+// Netlyser Copyright(C) 2019 Furkan Türkal
+// This program comes with ABSOLUTELY NO WARRANTY; This is free software,
+// and you are welcome to redistribute it under certain conditions; See
+// file LICENSE, which is part of this source code package, for details.
+// ====================================================
+
+use std::{cmp, collections::HashMap, fs, io, net::Ipv4Addr, str::FromStr, thread, time};
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+    Arc, Mutex, RwLock,
+};
+
+use pnet::util::MacAddr;
+
+use chrono::prelude::*;
+
+use rusqlite::Connection;
+
+use crate::config;
+use crate::db;
+use crate::error::{Error, Result};
+use crate::lease;
+use crate::net;
+use crate::oui;
+use crate::people;
+use crate::resolve;
+use crate::security;
+use crate::sniff;
+
+// Capacity of the queues between the scanners, the store and the notifier
+pub(crate) const QUEUE_SIZE: usize = 256;
+
+// How often a waiting scanner checks the stop request
+const STOP_POLL: time::Duration = time::Duration::from_millis(500);
+
+// Presence changes and the alerts, in the order they are stored
+#[derive(Debug, Clone)]
+pub enum Event {
+    Connected(db::Host),
+    Disconnected(db::Host),
+    // Seen for the first time and not in the config
+    Unknown(db::Host),
+    Alert(db::Alert),
+    // The first primary device of the person is connected
+    Arrived(db::Person),
+    // The last primary device of the person is disconnected
+    Departed(db::Person),
+}
+
+// Embeds the monitor into another program:
+//
+//     let handle = Monitor::builder()
+//         .config(config::get_config("netlyser.yaml")?)
+//         .store("/var/log/sweet-home.db")
+//         .build()?
+//         .start()?;
+//
+//     for event in handle.events() {
+//         ...
+//     }
+#[derive(Debug, Clone, Default)]
+pub struct MonitorBuilder {
+    config: Option<config::Config>,
+    networks: Vec<config::Network>,
+    scanner: Option<config::Scanner>,
+    store: Option<String>,
+    config_file: Option<String>,
+}
+
+impl MonitorBuilder {
+    pub fn config(mut self, config: config::Config) -> MonitorBuilder {
+        self.config = Some(config);
+        self
+    }
+
+    // The config is loaded from the file unless it is given, and reloaded
+    // whenever the file changes
+    pub fn config_file(mut self, path: &str) -> MonitorBuilder {
+        self.config_file = Some(path.to_string());
+        self
+    }
+
+    // Replaces the networks of the config, can be called multiple times
+    pub fn network(mut self, network: config::Network) -> MonitorBuilder {
+        self.networks.push(network);
+        self
+    }
+
+    // Overrides the scanner of every network
+    pub fn scanner(mut self, scanner: config::Scanner) -> MonitorBuilder {
+        self.scanner = Some(scanner);
+        self
+    }
+
+    // Path of the SQLite database file
+    pub fn store(mut self, path: &str) -> MonitorBuilder {
+        self.store = Some(path.to_string());
+        self
+    }
+
+    pub fn build(self) -> Result<Monitor> {
+        let config = match (self.config, &self.config_file) {
+            (Some(r), _) => r,
+            (None, Some(path)) => config::get_config(path)?,
+            (None, None) => return Err(Error::Io(io::Error::new(io::ErrorKind::NotFound, "no config is given"))),
+        };
+
+        let store = match self.store {
+            Some(r) => r,
+            None => return Err(Error::Io(io::Error::new(io::ErrorKind::NotFound, "no store is given"))),
+        };
+
+        let mut networks = if self.networks.is_empty() {
+            config.networks.clone()
+        } else {
+            self.networks
+        };
+
+        if networks.is_empty() {
+            return Err(Error::Network("no network to scan, please give one via '--network' or the 'networks' of the config".to_string()));
+        }
+
+        if let Some(scanner) = self.scanner {
+            for network in networks.iter_mut() {
+                network.scanner = Some(scanner);
+            }
+        }
+
+        for network in &networks {
+            if network.get_scanner(&config.general) == config::Scanner::Leases && network.get_lease_file(&config.general).is_none() {
+                return Err(Error::Config(config::ErrorType::ParseError));
+            }
+        }
+
+        Ok(Monitor {
+            config: config,
+            networks: networks,
+            store: store,
+            config_file: self.config_file,
+        })
+    }
+}
+
+pub struct Monitor {
+    config: config::Config,
+    networks: Vec<config::Network>,
+    store: String,
+    config_file: Option<String>,
+}
+
+impl Monitor {
+    pub fn builder() -> MonitorBuilder {
+        MonitorBuilder::default()
+    }
+
+    // Starts a scanner for every network and interface, the failures of the
+    // networks (i.e. no such interface) are returned before anything starts.
+    // A network without a gateway is still watched, see 'Handle::degraded()'
+    pub fn start(self) -> Result<Handle> {
+        let conn = db::init_db(&self.store)?;
+
+        let ouidb = match oui::OuiDb::new(&self.config.general.oui_file) {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("[monitor::start()]: unable to load the OUI file, only the embedded fallback vendors are resolved, run 'netlyser oui update'. Err: {}", e);
+                oui::OuiDb::embedded()
+            }
+        };
+
+        info!("[monitor::start()]: OUI database loaded with {} vendors", ouidb.len());
+
+        let shared = share(self.config.clone());
+        let ouidb = Arc::new(ouidb);
+
+        let mut targets: Vec<Target> = vec![];
+        for network in &self.networks {
+            targets.extend(get_targets(network, &self.config.general)?);
+        }
+
+        let degraded: Vec<String> = targets.iter()
+            .filter(|t| t.gateway.is_none())
+            .map(|t| format!("{} on {}", t.label, t.interface))
+            .collect();
+
+        if let Some(last) = db::get_last_run(&conn)? {
+            if last.stop.is_none() {
+                let time = Local.timestamp(last.start, 0).format("%Y-%m-%d %H:%M:%S");
+                warn!("[monitor::start()]: the run started at {} is not stopped cleanly, its last changes may be missing", time);
+            }
+        }
+
+        let run = db::add_run(&conn)?;
+
+        // Presence of every target, the scanners start only if all of them are restored
+        let mut restored: Vec<(Target, Vec<db::Host>)> = vec![];
+        for target in targets {
+            let olds = restore(&target, &self.config, &conn)?;
+            restored.push((target, olds));
+        }
+
+        // The store thread owns the only connection from now on
+        drop(conn);
+
+        let (notifier_tx, notifier_rx) = mpsc::sync_channel::<Event>(QUEUE_SIZE);
+        let (events_tx, events_rx) = mpsc::sync_channel::<Event>(QUEUE_SIZE);
+        let (done_tx, done_rx) = mpsc::channel::<Result<()>>();
+
+        let sinks = Sinks {
+            notifier: notifier_tx,
+            events: events_tx,
+        };
+
+        let (store_tx, writer) = spawn_store(&self.store, shared.clone(), sinks);
+
+        let mut workers: Vec<thread::JoinHandle<()>> = vec![writer];
+
+        let live = shared.clone();
+        workers.push(thread::spawn(move || {
+            for e in notifier_rx {
+                db::notify_event(&e, &current(&live).config);
+            }
+        }));
+
+        let people = Arc::new(Mutex::new(people::Tracker::new()));
+        let present = Arc::new(Mutex::new(Present::default()));
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let reload = Arc::new(AtomicBool::new(false));
+
+        if let Some(ref path) = self.config_file {
+            watch_config(path, shared.clone(), reload.clone(), stop.clone());
+        }
+
+        for (target, olds) in restored {
+            people.lock()?.seed(&target.label, &target.interface, &olds);
+            present.lock()?.update(&target.label, &target.interface, &olds);
+
+            let pipeline = Pipeline {
+                ouidb: ouidb.clone(),
+                people: people.clone(),
+                present: present.clone(),
+                store: store_tx.clone(),
+            };
+
+            let retire = Retire {
+                label: target.label.to_string(),
+                interface: target.interface.to_string(),
+                people: people.clone(),
+                present: present.clone(),
+            };
+
+            let (shared, stop, done) = (shared.clone(), stop.clone(), done_tx.clone());
+            thread::spawn(move || {
+                let _retire = retire;
+                let _ = done.send(scan(target, shared, pipeline, olds, stop));
+            });
+        }
+
+        Ok(Handle {
+            events: events_rx,
+            done: done_rx,
+            stop: stop,
+            reload: reload,
+            workers: workers,
+            store: self.store,
+            run: run,
+            degraded: degraded,
+        })
+    }
+}
+
+// Config and the host map those are swapped together on reload
+#[derive(Clone)]
+pub(crate) struct Live {
+    pub config: config::Config,
+    pub hostmap: Arc<config::HostMap>,
+}
+
+pub(crate) type Shared = Arc<RwLock<Live>>;
+
+pub(crate) fn share(config: config::Config) -> Shared {
+    Arc::new(RwLock::new(Live {
+        hostmap: Arc::new(config::get_host_map(config.clone())),
+        config: config,
+    }))
+}
+
+// Nothing panics while holding the lock, so a poisoned one is still consistent
+pub(crate) fn current(shared: &Shared) -> Live {
+    shared.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+// Polls the config file and reloads it when it is modified or a reload is requested
+fn watch_config(path: &str, shared: Shared, reload: Arc<AtomicBool>, stop: Arc<AtomicBool>) -> thread::JoinHandle<()> {
+    let path = path.to_string();
+
+    thread::spawn(move || {
+        let mut modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+
+        while !stop.load(Ordering::SeqCst) {
+            let m = fs::metadata(&path).and_then(|m| m.modified()).ok();
+
+            if m != modified || reload.swap(false, Ordering::SeqCst) {
+                modified = m;
+                reload_config(&path, &shared);
+            }
+
+            thread::sleep(time::Duration::from_secs(1));
+        }
+    })
+}
+
+// The new config is applied only if it passes the same check as 'config check'.
+// The networks and the scanners can not be changed while running, the rest is
+// picked up by the next scan
+fn reload_config(path: &str, shared: &Shared) {
+    let mut config = match config::get_config(path) {
+        Ok(r) => r,
+        Err(e) => {
+            warn!("[monitor::reload_config()]: keeping the old config, {} is invalid. Err: {}", path, e);
+            return;
+        }
+    };
+
+    let hostmap = Arc::new(config::get_host_map(config.clone()));
+
+    let mut live = shared.write().unwrap_or_else(|e| e.into_inner());
+
+    config.is_root = live.config.is_root;
+    config.dry_run = live.config.dry_run;
+
+    let networks = |c: &config::Config| c.networks.iter().map(|n| n.get_label()).collect::<Vec<String>>();
+    if networks(&config) != networks(&live.config) {
+        warn!("[monitor::reload_config()]: the networks are changed, they are applied on the next start");
+    }
+
+    *live = Live {
+        config: config,
+        hostmap: hostmap,
+    };
+
+    info!("[monitor::reload_config()]: config reloaded from {}", path);
+}
+
+// Part of a scan those needs the database, it is done by the store thread so
+// the scanners never wait for SQLite
+pub(crate) struct Batch {
+    pub label: String,
+    pub interface: String,
+    // Gateway of the scanner, the MAC learned by the ARP watch is kept while it is the same
+    pub gateway: Option<net::Gateway>,
+    // Entries of the interface, None for a passive sighting
+    pub arps: Option<Vec<net::ArpEntry>>,
+    // Hosts of the scanner, saved as its presence
+    pub hosts: Vec<db::Host>,
+    // Hosts of the network before and after the scan
+    pub olds: Vec<db::Host>,
+    pub present: Vec<db::Host>,
+    // Arrivals and departures of the people
+    pub people: Vec<Event>,
+    // The changes of the first scan after a restart may be stored without notifying
+    pub notify: bool,
+    // Only the scans repeat the unacknowledged alerts
+    pub repeat: bool,
+    pub time: i64,
+}
+
+pub(crate) enum Record {
+    Scan(Batch),
+    // Answered once the records sent before are done, i.e. by a replay
+    Flush(mpsc::Sender<()>),
+}
+
+// Owns the only connection of the monitor and handles the records in the order
+// they are sent until every sender is dropped. The unknown devices and the
+// alerts are found here, then every event is stored, streamed and notified
+pub(crate) fn spawn_store(db: &String, shared: Shared, sinks: Sinks) -> (SyncSender<Record>, thread::JoinHandle<()>) {
+    let (tx, rx) = mpsc::sync_channel::<Record>(QUEUE_SIZE);
+
+    let db = db.to_string();
+    let writer = thread::spawn(move || {
+        let conn = match db::open_db(&db) {
+            Ok(r) => r,
+            Err(e) => {
+                error!("[monitor::spawn_store()]: unable to open {}, nothing is stored. Err: {}", db, e);
+                return;
+            }
+        };
+
+        let mut store = Store {
+            conn: conn,
+            sinks: sinks,
+            watches: HashMap::new(),
+        };
+
+        for r in rx {
+            match r {
+                Record::Scan(batch) => store.handle(batch, &current(&shared).config),
+                Record::Flush(done) => {
+                    let _ = done.send(());
+                }
+            }
+        }
+    });
+
+    (tx, writer)
+}
+
+struct Store {
+    conn: Connection,
+    sinks: Sinks,
+    // ARP watch of every scanner, with the gateway the scanner last gave
+    watches: HashMap<(String, String), (Option<net::Gateway>, security::ArpWatch)>,
+}
+
+impl Store {
+    fn handle(&mut self, batch: Batch, conf: &config::Config) {
+        let time = batch.time;
+
+        if let Some(ref arps) = batch.arps {
+            let gateway = batch.gateway.clone();
+            let watch = self.watches
+                .entry((batch.label.to_string(), batch.interface.to_string()))
+                .or_insert_with(|| (gateway.clone(), security::ArpWatch::new(gateway.clone())));
+
+            if watch.0 != gateway {
+                watch.0 = gateway.clone();
+                watch.1.set_gateway(gateway);
+            }
+
+            let alerts = watch.1.check(&self.conn, arps, conf, time);
+            self.send(alerts.into_iter().map(Event::Alert), time, true);
+        }
+
+        let mut changes = db::get_changes(&batch.olds, &batch.present, conf, &self.conn, time);
+        changes.extend(batch.people);
+        self.send(changes, time, batch.notify);
+
+        security::rearm_alerts(&self.conn, &batch.olds, &batch.present);
+        self.send(security::check_allowlist(&self.conn, &batch.hosts, conf, time).into_iter().map(Event::Alert), time, true);
+
+        // Repeats are only notified, they are not new events
+        if batch.repeat {
+            for a in security::repeat_alerts(&self.conn, conf, time) {
+                self.sinks.notify(Event::Alert(a));
+            }
+        }
+
+        if let Err(e) = db::save_presence(&mut self.conn, &batch.label, &batch.interface, &batch.hosts, time) {
+            warn!("[monitor::Store::handle()]: unable to save the presence of {} on {}. Err: {}", batch.label, batch.interface, e);
+        }
+    }
+
+    // Stores and streams the events, notifies them unless they are silent
+    fn send<I: IntoIterator<Item = Event>>(&self, events: I, time: i64, notify: bool) {
+        for e in events {
+            db::store_event(&self.conn, &e, time);
+
+            // The receiver may be dropped, i.e. by the cli
+            if let Err(TrySendError::Full(e)) = self.sinks.events.try_send(e.clone()) {
+                warn!("[monitor::Store::send()]: event queue is full, dropped {:?}", e);
+            }
+
+            if notify {
+                self.sinks.notify(e);
+            }
+        }
+    }
+}
+
+// Outputs of the store, every consumer runs on its own thread so a slow one
+// (i.e. a notification daemon that is not responding) can not delay the store
+pub(crate) struct Sinks {
+    pub notifier: SyncSender<Event>,
+    pub events: SyncSender<Event>,
+}
+
+impl Sinks {
+    fn notify(&self, event: Event) {
+        if let Err(TrySendError::Full(e)) = self.notifier.try_send(event) {
+            warn!("[monitor::notify()]: notification queue is full, dropped {:?}", e);
+        }
+    }
+}
+
+pub struct Handle {
+    events: Receiver<Event>,
+    done: Receiver<Result<()>>,
+    stop: Arc<AtomicBool>,
+    reload: Arc<AtomicBool>,
+    workers: Vec<thread::JoinHandle<()>>,
+    store: String,
+    run: i64,
+    degraded: Vec<String>,
+}
+
+impl Handle {
+    // Blocks until the next event, ends when every scanner is stopped
+    pub fn events(&self) -> mpsc::Iter<Event> {
+        self.events.iter()
+    }
+
+    pub fn receiver(&self) -> &Receiver<Event> {
+        &self.events
+    }
+
+    // Networks those are watched without the gateway checks as there is no
+    // default route on the interface, as 'label on interface'
+    pub fn degraded(&self) -> &Vec<String> {
+        &self.degraded
+    }
+
+    // Asks the scanners to stop once their current scan is done, it can be set
+    // from anywhere (i.e. a signal handler) while waiting
+    pub fn stopper(&self) -> Arc<AtomicBool> {
+        self.stop.clone()
+    }
+
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+
+    // Asks to reload the config file given to the builder, i.e. on SIGHUP
+    pub fn reloader(&self) -> Arc<AtomicBool> {
+        self.reload.clone()
+    }
+
+    pub fn reload(&self) {
+        self.reload.store(true, Ordering::SeqCst);
+    }
+
+    // Blocks until every scanner is stopped, the first failure (i.e. nmap is not
+    // installed) stops the others, the failed scans are retried by the scanners.
+    // The queued events are stored and notified, then the run is marked as stopped
+    pub fn wait(self) -> Result<()> {
+        drop(self.events);
+
+        let mut result: Result<()> = Ok(());
+
+        for r in self.done.iter() {
+            if let Err(e) = r {
+                warn!("[monitor::wait()]: scanner failed, stopping the others. Err: {}", e);
+                self.stop.store(true, Ordering::SeqCst);
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+
+        let run = self.run;
+
+        for w in self.workers {
+            let _ = w.join();
+        }
+
+        if let Err(e) = db::open_db(&self.store).and_then(|c| db::stop_run(&c, run)) {
+            warn!("[monitor::wait()]: unable to mark the run as stopped. Err: {}", e);
+        }
+
+        result
+    }
+}
+
+// A network watched through an interface
+struct Target {
+    label: String,
+    network: net::ScanInfo,
+    interface: String,
+    // Given by the user, so nmap is forced to use it
+    explicit: bool,
+    local: Ipv4Addr,
+    // None if the interface has no default route, i.e. a VLAN or a secondary NIC
+    gateway: Option<net::Gateway>,
+    // The interval and the round are taken from the config on every scan
+    source: config::Network,
+    scanner: config::Scanner,
+    lease_file: Option<String>,
+}
+
+fn get_targets(conf: &config::Network, general: &config::General) -> Result<Vec<Target>> {
+    let network = net::ScanInfo::from_str(&conf.network).map_err(|e| Error::Network(e.to_string()))?;
+
+    let mut locals: Vec<(String, Ipv4Addr, bool)> = vec![];
+
+    if conf.interfaces.is_empty() {
+        match net::get_local_addr(&network) {
+            Ok((interface, local)) => locals.push((interface, local, false)),
+            Err(e) => return Err(Error::Network(format!("unable to find the local address in {}: {}", network, e))),
+        }
+    } else {
+        for interface in &conf.interfaces {
+            match net::get_interface_addr(interface, &network) {
+                Ok(local) => locals.push((interface.to_string(), local, true)),
+                Err(e) => return Err(Error::Interface(interface.to_string(), e)),
+            }
+        }
+    }
+
+    let mut targets: Vec<Target> = vec![];
+
+    for (interface, local, explicit) in locals {
+        info!("Local address {} on {}", local, interface);
+
+        let gateway = match net::get_gateway(&interface, local) {
+            Ok(r) => {
+                info!("Gateway {} ({}) on {}", r.ip, r.mac, interface);
+                Some(r)
+            }
+            Err(e) => {
+                warn!("[monitor::get_targets()]: no gateway on {}, the gateway checks are disabled. Err: {}", interface, e);
+                None
+            }
+        };
+
+        targets.push(Target {
+            label: conf.get_label(),
+            network: network.clone(),
+            interface: interface,
+            explicit: explicit,
+            local: local,
+            gateway: gateway,
+            source: conf.clone(),
+            scanner: conf.get_scanner(general),
+            lease_file: conf.get_lease_file(general),
+        });
+    }
+
+    Ok(targets)
+}
+
+impl Target {
+    // The network of a reloaded config overrides the one given at the start
+    fn get_network<'a>(&'a self, conf: &'a config::Config) -> &'a config::Network {
+        conf.networks.iter().find(|n| n.get_label() == self.label).unwrap_or(&self.source)
+    }
+}
+
+// Presence of the target when the monitor is stopped, so the present hosts
+// are not connected again
+fn restore(target: &Target, conf: &config::Config, conn: &Connection) -> Result<Vec<db::Host>> {
+    if conf.presence.policy == config::RestorePolicy::Reset {
+        return Ok(vec![]);
+    }
+
+    let (hosts, time) = db::get_presence(conn, &target.label, &target.interface)?;
+
+    // Nothing is saved yet, i.e. the first start
+    if hosts.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let age = (Local::now().timestamp() - time).max(0) as u64 * 1000;
+
+    if conf.presence.max_age != 0 && age > conf.presence.max_age {
+        info!("[monitor::restore()]: saved presence of {} on {} is too old, ignored", target.label, target.interface);
+        return Ok(vec![]);
+    }
+
+    info!("[monitor::restore()]: restored {} host(s) of {} on {}", hosts.len(), target.label, target.interface);
+
+    Ok(hosts)
+}
+
+// Nothing panics while holding the lock, so a poisoned one is still consistent
+fn track(people: &Mutex<people::Tracker>, state: &ScanState, hosts: &Vec<db::Host>) -> Vec<Event> {
+    people.lock().unwrap_or_else(|e| e.into_inner()).update(&state.label, &state.interface, hosts)
+}
+
+// Present hosts of every scanner, so a host of a network watched through
+// multiple interfaces is connected and disconnected once
+#[derive(Debug, Default)]
+pub(crate) struct Present {
+    // By the network label and the interface
+    hosts: HashMap<(String, String), Vec<db::Host>>,
+}
+
+impl Present {
+    // Sets the hosts of the scanner, returns the ones of the network before and after
+    pub fn update(&mut self, network: &str, interface: &str, hosts: &Vec<db::Host>) -> (Vec<db::Host>, Vec<db::Host>) {
+        let olds = self.get(network);
+        self.hosts.insert((network.to_string(), interface.to_string()), hosts.clone());
+        (olds, self.get(network))
+    }
+
+    pub fn remove(&mut self, network: &str, interface: &str) {
+        self.hosts.remove(&(network.to_string(), interface.to_string()));
+    }
+
+    fn get(&self, network: &str) -> Vec<db::Host> {
+        let mut hosts: Vec<db::Host> = vec![];
+
+        for (_, v) in self.hosts.iter().filter(|(k, _)| k.0 == network) {
+            for h in v {
+                if !hosts.contains(h) {
+                    hosts.push(h.clone());
+                }
+            }
+        }
+
+        hosts
+    }
+}
+
+// Forgets the hosts of a scanner once its thread exits, even by a panic, so
+// they are not kept as present forever
+struct Retire {
+    label: String,
+    interface: String,
+    people: Arc<Mutex<people::Tracker>>,
+    present: Arc<Mutex<Present>>,
+}
+
+impl Drop for Retire {
+    fn drop(&mut self) {
+        info!("[monitor::Retire::drop()]: scanner of {} on {} is exited", self.label, self.interface);
+
+        self.people.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.label, &self.interface);
+        self.present.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.label, &self.interface);
+    }
+}
+
+// Shared by the scanners of a monitor, or by a replay
+pub(crate) struct Pipeline {
+    pub ouidb: Arc<oui::OuiDb>,
+    pub people: Arc<Mutex<people::Tracker>>,
+    pub present: Arc<Mutex<Present>>,
+    pub store: SyncSender<Record>,
+}
+
+impl Pipeline {
+    // Nothing must be lost, so a full store holds the scanner back
+    fn store(&self, batch: Batch) {
+        if self.store.send(Record::Scan(batch)).is_err() {
+            warn!("[monitor::Pipeline::store()]: the store is stopped, the scan is not stored");
+        }
+    }
+}
+
+// Hosts of the network before and after the scanner found its hosts
+fn get_present(pipeline: &Pipeline, state: &ScanState, news: &Vec<db::Host>) -> (Vec<db::Host>, Vec<db::Host>) {
+    // Nothing panics while holding the lock, so a poisoned one is still consistent
+    pipeline.present.lock().unwrap_or_else(|e| e.into_inner()).update(&state.label, &state.interface, news)
+}
+
+// What a scanner remembers between the scans
+pub(crate) struct ScanState {
+    pub label: String,
+    pub interface: String,
+    pub local: Ipv4Addr,
+    pub gateway: Option<net::Gateway>,
+    pub olds: Vec<db::Host>,
+    pub resolver: Option<resolve::Resolver>,
+    // The changes of the first scan are stored without notifying
+    pub silent: bool,
+}
+
+impl ScanState {
+    pub fn new(label: &str, interface: &str, local: Ipv4Addr, gateway: Option<net::Gateway>, olds: Vec<db::Host>) -> ScanState {
+        ScanState {
+            label: label.to_string(),
+            interface: interface.to_string(),
+            local: local,
+            gateway: gateway,
+            olds: olds,
+            resolver: None,
+            silent: false,
+        }
+    }
+}
+
+// A completed scan of the target
+pub(crate) struct ScanResult {
+    pub source: db::Source,
+    pub hosts: Vec<net::Host>,
+    // Entries of the interface only
+    pub arps: Vec<net::ArpEntry>,
+    // Seen by the passive discovery, they are kept as present
+    pub passives: Vec<db::Host>,
+    // Unix timestamp the changes are stored with
+    pub time: i64,
+}
+
+// Finds the changes of the scan and sends them to the store
+pub(crate) fn process(state: &mut ScanState, pipeline: &Pipeline, live: &Live, scan: ScanResult) {
+    let mut news = db::migrate_to_host_list(&live.hostmap, &pipeline.ouidb, &mut state.resolver, state.gateway.as_ref(), scan.source, scan.hosts, net::get_arp_map(&scan.arps), state.local);
+
+    for h in news.iter_mut() {
+        h.network = Some(state.label.to_string());
+    }
+
+    // Sleeping phones may miss the scan, so the hosts seen passively during
+    // the last interval are kept as present
+    for h in scan.passives {
+        if !news.iter().any(|n| n.mac == h.mac) {
+            news.push(h);
+        }
+    }
+
+    let (olds, present) = get_present(pipeline, state, &news);
+
+    pipeline.store(Batch {
+        label: state.label.to_string(),
+        interface: state.interface.to_string(),
+        gateway: state.gateway.clone(),
+        arps: Some(scan.arps),
+        hosts: news.clone(),
+        olds: olds,
+        present: present,
+        people: track(&pipeline.people, state, &news),
+        notify: !state.silent,
+        repeat: true,
+        time: scan.time,
+    });
+
+    state.silent = false;
+    state.olds = news;
+}
+
+// Scans the target until it is stopped
+fn scan(target: Target, shared: Shared, pipeline: Pipeline, olds: Vec<db::Host>, stop: Arc<AtomicBool>) -> Result<()> {
+    let conf = current(&shared).config;
+
+    let ipmask = target.network.to_string();
+    let nmap_interface = if target.explicit { Some(target.interface.clone()) } else { None };
+
+    let mut state = ScanState::new(&target.label, &target.interface, target.local, target.gateway.clone(), olds);
+
+    if conf.general.resolve_hostnames {
+        state.resolver = Some(resolve::Resolver::new(time::Duration::from_millis(conf.general.resolve_ttl), time::Duration::from_millis(conf.general.mdns_timeout)));
+    }
+
+    // The changes while the monitor is down are found by the first scan
+    state.silent = conf.presence.policy == config::RestorePolicy::Silent && !state.olds.is_empty();
+
+    let (tx, rx) = mpsc::channel::<sniff::Sighting>();
+
+    if conf.passive.enabled {
+        if let Err(e) = sniff::listen(&target.interface, tx.clone(), stop.clone()) {
+            warn!("Unable to start the passive discovery on {}. Err: {}", target.interface, e);
+        }
+    }
+
+    if let Some(ref path) = target.lease_file {
+        lease::watch(path, tx.clone(), stop.clone());
+    }
+
+    drop(tx);
+
+    // Hosts seen by the passive discovery, with the time they are last seen
+    let mut passives: HashMap<MacAddr, (db::Host, time::Instant)> = HashMap::new();
+
+    loop {
+        if stop.load(Ordering::SeqCst) {
+            info!("[monitor::scan()]: stopped scanning {} on {}", target.label, target.interface);
+            return Ok(());
+        }
+
+        // Every scan uses the latest config
+        let live = current(&shared);
+        let Live { config: ref conf, ref hostmap } = live;
+
+        let network = target.get_network(conf);
+        let duration = time::Duration::from_millis(network.get_interval(&conf.general));
+        let round = network.get_round(&conf.general);
+
+        let scanned = match target.scanner {
+            config::Scanner::Nmap => match net::do_scan_nmap(&ipmask, nmap_interface.as_ref(), &conf.nmap.get_args(), round) {
+                Ok(r) => Some((db::Source::Nmap, r)),
+                Err(net::NmapError::NotInstalled) => return Err(net::NmapError::NotInstalled.into()),
+                // Reporting every host as disconnected would be wrong, they are kept and
+                // the scan is retried on the next interval
+                Err(e) => {
+                    warn!("[monitor::scan()]: scan of {} on {} failed, keeping the last hosts. Err: {}", target.label, target.interface, e);
+                    None
+                }
+            },
+            // Validated by the config
+            config::Scanner::Leases => Some((db::Source::Lease, lease::do_scan_leases(target.lease_file.as_ref().unwrap()))),
+        };
+
+        let scanned = scanned.and_then(|(source, hosts)| match net::do_scan_arp() {
+            Ok(r) => Some((source, hosts, r.into_iter().filter(|e| e.device == target.interface).collect::<Vec<net::ArpEntry>>())),
+            Err(e) => {
+                warn!("[monitor::scan()]: unable to read the ARP table for {} on {}, keeping the last hosts. Err: {}", target.label, target.interface, e);
+                None
+            }
+        });
+
+        if let Some((source, hosts, arps)) = scanned {
+
+            // The signal is delivered to nmap too, so the results of an interrupted scan are incomplete
+            if stop.load(Ordering::SeqCst) {
+                info!("[monitor::scan()]: stopped scanning {} on {}", target.label, target.interface);
+                return Ok(());
+            }
+
+            passives.retain(|_, v| v.1.elapsed() < duration);
+
+            let result = ScanResult {
+                source: source,
+                hosts: hosts,
+                arps: arps,
+                passives: passives.values().map(|(h, _)| h.clone()).collect(),
+                time: Local::now().timestamp(),
+            };
+
+            process(&mut state, &pipeline, &live, result);
+        }
+
+        // Arrivals are handled instantly while waiting for the next scan, the
+        // departures are left to the scan
+        let deadline = time::Instant::now() + duration;
+        loop {
+            let now = time::Instant::now();
+            if now >= deadline || stop.load(Ordering::SeqCst) {
+                break;
+            }
+
+            // Waits in slices to notice the stop request
+            let slice = cmp::min(deadline - now, STOP_POLL);
+
+            let sighting = match rx.recv_timeout(slice) {
+                Ok(r) => r,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => {
+                    thread::sleep(slice);
+                    continue;
+                }
+            };
+
+            if !target.network.contains(&sighting.ip) {
+                continue;
+            }
+
+            let mut h = match db::migrate_sighting(hostmap, &pipeline.ouidb, state.gateway.as_ref(), &sighting, state.local) {
+                Some(r) => r,
+                None => continue,
+            };
+
+            h.network = Some(target.label.to_string());
+
+            if h.mac == MacAddr::zero() {
+                continue;
+            }
+
+            passives.insert(h.mac, (h.clone(), time::Instant::now()));
+
+            if !state.olds.iter().any(|o| o.mac == h.mac) {
+                let mut news = state.olds.clone();
+                news.push(h);
+
+                let (olds, present) = get_present(&pipeline, &state, &news);
+
+                pipeline.store(Batch {
+                    label: state.label.to_string(),
+                    interface: state.interface.to_string(),
+                    gateway: state.gateway.clone(),
+                    arps: None,
+                    hosts: news.clone(),
+                    olds: olds,
+                    present: present,
+                    people: track(&pipeline.people, &state, &news),
+                    notify: true,
+                    repeat: false,
+                    time: Local::now().timestamp(),
+                });
+
+                state.olds = news;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{env, process};
+
+    use crate::serde_yaml;
+
+    fn get_host(ip: &str, mac: &str) -> db::Host {
+        let mut h = db::Host::new();
+        h.set_ip(ip.parse().unwrap());
+        h.set_mac(MacAddr::from_str(mac).unwrap());
+        h
+    }
+
+    #[test]
+    fn present_reports_a_multi_homed_host_once() {
+        let mut present = Present::default();
+        let phone = vec![get_host("192.168.1.20", "02:00:00:00:00:20")];
+
+        assert_eq!(present.update("home", "eth0", &phone), (vec![], phone.clone()));
+
+        // Seen through the second interface too
+        assert_eq!(present.update("home", "wlan0", &phone), (phone.clone(), phone.clone()));
+
+        // Still seen through the first one
+        assert_eq!(present.update("home", "wlan0", &vec![]), (phone.clone(), phone.clone()));
+        assert_eq!(present.update("home", "eth0", &vec![]), (phone.clone(), vec![]));
+    }
+
+    #[test]
+    fn present_keeps_the_networks_apart() {
+        let mut present = Present::default();
+        let phone = vec![get_host("192.168.1.20", "02:00:00:00:00:20")];
+
+        present.update("home", "eth0", &phone);
+
+        assert_eq!(present.update("guest", "eth1", &vec![]), (vec![], vec![]));
+        assert_eq!(present.update("home", "wlan0", &vec![]), (phone.clone(), phone.clone()));
+    }
+
+    #[test]
+    fn reload_keeps_the_old_config_if_the_new_one_is_invalid() {
+        let path = env::temp_dir().join(format!("netlyser-reload-{}.yaml", process::id())).to_string_lossy().to_string();
+        let general = "general:\n  interval: 1000\n  round: 1\n  notify_on_connect: true\n  notify_on_disconnect: true\n";
+        let hosts = "hosts:\n  - mac: \"01:23:45:67:89:AB\"\n    name: a\n    device: b\n";
+
+        fs::write(&path, format!("{}{}", general, hosts)).unwrap();
+        let shared = share(config::get_config(&path).unwrap());
+
+        // A busy scan loop
+        fs::write(&path, format!("{}{}", general.replace("interval: 1000", "interval: 0"), hosts)).unwrap();
+        reload_config(&path, &shared);
+        assert_eq!(current(&shared).config.general.interval, 1000);
+
+        // The last one would silently win in the host map
+        fs::write(&path, format!("{}{}  - mac: \"01:23:45:67:89:ab\"\n    name: c\n    device: d\n", general.replace("interval: 1000", "interval: 2000"), hosts)).unwrap();
+        reload_config(&path, &shared);
+        let live = current(&shared);
+        assert_eq!(live.config.general.interval, 1000);
+        assert_eq!(live.config.hosts.len(), 1);
+
+        fs::write(&path, format!("{}{}", general.replace("interval: 1000", "interval: 2000"), hosts)).unwrap();
+        reload_config(&path, &shared);
+        assert_eq!(current(&shared).config.general.interval, 2000);
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn store_finds_stores_and_notifies_the_changes() {
+        let db = env::temp_dir().join(format!("netlyser-store-{}.db", process::id())).to_string_lossy().to_string();
+        let _ = fs::remove_file(&db);
+        db::init_db(&db).unwrap();
+
+        let content = "general:\n  interval: 1000\n  round: 1\n  notify_on_connect: true\n  notify_on_disconnect: true\nhosts: []\n";
+        let shared = share(serde_yaml::from_str(content).unwrap());
+
+        let (notifier, notifications) = mpsc::sync_channel::<Event>(QUEUE_SIZE);
+        let (events, streamed) = mpsc::sync_channel::<Event>(QUEUE_SIZE);
+        let (store, writer) = spawn_store(&db, shared, Sinks {
+            notifier: notifier,
+            events: events,
+        });
+
+        let phone = vec![get_host("192.168.1.20", "02:00:00:00:00:20")];
+        let batch = |olds: &Vec<db::Host>, hosts: &Vec<db::Host>, notify: bool, time: i64| Batch {
+            label: "home".to_string(),
+            interface: "eth0".to_string(),
+            gateway: None,
+            arps: Some(vec![]),
+            hosts: hosts.clone(),
+            olds: olds.clone(),
+            present: hosts.clone(),
+            people: vec![],
+            notify: notify,
+            repeat: true,
+            time: time,
+        };
+
+        // The first scan after a restart is only stored
+        store.send(Record::Scan(batch(&vec![], &phone, false, 1))).unwrap();
+        store.send(Record::Scan(batch(&phone, &vec![], true, 2))).unwrap();
+        drop(store);
+        writer.join().unwrap();
+
+        let kinds = |events: Vec<Event>| events.iter().map(|e| match e {
+            Event::Connected(_) => "connected",
+            Event::Disconnected(_) => "disconnected",
+            Event::Unknown(_) => "unknown",
+            _ => "other",
+        }).collect::<Vec<&str>>();
+
+        assert_eq!(kinds(streamed.try_iter().collect()), vec!["connected", "unknown", "disconnected"]);
+        assert_eq!(kinds(notifications.try_iter().collect()), vec!["disconnected"]);
+
+        let conn = db::open_db(&db).unwrap();
+        let logs = db::get_logs(&conn, None, 10).unwrap();
+        assert_eq!(logs.iter().map(|l| l.time).collect::<Vec<i64>>(), vec![2, 1, 1]);
+        assert!(db::get_presence(&conn, "home", "eth0").unwrap().0.is_empty());
+
+        let _ = fs::remove_file(&db);
+    }
+}
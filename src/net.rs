@@ -7,30 +7,39 @@
 
 use std::{
     collections::HashMap,
+    fmt,
     fs::OpenOptions,
-    io::{BufRead, BufReader, Error, ErrorKind},
-    net::Ipv4Addr,
-    process::exit,
+    io::{BufRead, BufReader, Error, ErrorKind, Read},
+    net::{IpAddr, Ipv4Addr, UdpSocket},
     process::{Command, Stdio},
     str::FromStr,
+    thread,
+    time::Duration,
 };
 
-use regex::Regex;
-
+use pnet::datalink;
 use pnet::util::MacAddr;
 
-use tempfile::NamedTempFile;
 
-use crate::run::ExitCodes;
+use serde_xml_rs::from_str;
 
-use serde_xml_rs::from_reader;
+const RTF_GATEWAY: u16 = 0x2;
+const ARP_TABLE: &str = "/proc/net/arp";
+const ROUTE_TABLE: &str = "/proc/net/route";
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Gateway {
     pub ip: Ipv4Addr,
     pub mac: MacAddr,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArpEntry {
+    pub ip: Ipv4Addr,
+    pub mac: MacAddr,
+    pub device: String,
+}
+
 // ip  : IP to scan (192.168.1.0)
 // msak: Net Mask to scan (/24)
 #[derive(Debug, Clone)]
@@ -39,6 +48,37 @@ pub struct ScanInfo {
     pub mask: u8,
 }
 
+impl ScanInfo {
+    pub fn contains(&self, ip: &Ipv4Addr) -> bool {
+        let bits: u32 = if self.mask == 0 { 0 } else { !0u32 << (32 - self.mask as u32) };
+        (u32::from(*ip) & bits) == (u32::from(self.addr) & bits)
+    }
+}
+
+impl FromStr for ScanInfo {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ScanInfo, Error> {
+        let invalid = || Error::new(ErrorKind::InvalidInput, format!("invalid network: {}", s));
+
+        let mut x = s.splitn(2, '/');
+        let addr: Ipv4Addr = x.next().unwrap_or("").parse().map_err(|_| invalid())?;
+        let mask: u8 = x.next().unwrap_or("32").parse().map_err(|_| invalid())?;
+
+        if mask > 32 {
+            return Err(invalid());
+        }
+
+        Ok(ScanInfo { addr: addr, mask: mask })
+    }
+}
+
+impl fmt::Display for ScanInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.mask)
+    }
+}
+
 #[derive(PartialEq, Debug, Deserialize)]
 pub struct Times {
     pub srtt: String,
@@ -62,13 +102,63 @@ pub struct Status {
     pub reason_ttl: String,
 }
 
+#[derive(PartialEq, Debug, Deserialize)]
+pub struct Hostname {
+    pub name: String,
+
+    #[serde(rename = "type", default)]
+    pub kind: String,
+}
+
+#[derive(PartialEq, Debug, Default, Deserialize)]
+pub struct Hostnames {
+    #[serde(rename = "hostname", default)]
+    pub hostname: Vec<Hostname>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Host {
     #[serde(rename = "status")]
     pub status: Status,
 
-    #[serde(rename = "address")]
+    // ipv4, ipv6 and mac addresses in any order
+    #[serde(rename = "address", default)]
     pub address: Vec<Address>,
+
+    #[serde(rename = "hostnames", default)]
+    pub hostnames: Hostnames,
+
+    // DHCP client-id, only reported by the lease files
+    #[serde(skip)]
+    pub client_id: Option<String>,
+}
+
+#[derive(PartialEq, Debug, Deserialize)]
+pub struct Finished {
+    pub time: String,
+
+    #[serde(default)]
+    pub elapsed: String,
+
+    // 'success' or 'error', older versions do not report it
+    #[serde(default)]
+    pub exit: String,
+
+    #[serde(default)]
+    pub errormsg: String,
+}
+
+#[derive(PartialEq, Debug, Deserialize)]
+pub struct HostStats {
+    pub up: u32,
+    pub down: u32,
+    pub total: u32,
+}
+
+#[derive(PartialEq, Debug, Deserialize)]
+pub struct RunStats {
+    pub finished: Finished,
+    pub hosts: HostStats,
 }
 
 #[derive(Debug, Deserialize)]
@@ -82,143 +172,472 @@ pub struct NmapRun {
 
     #[serde(rename = "host", default)]
     pub hosts: Vec<Host>,
+
+    // Written at the end, so it is missing if nmap is killed
+    #[serde(default)]
+    pub runstats: Option<RunStats>,
+}
+
+#[derive(Debug)]
+pub enum NmapError {
+    NotInstalled,
+    Io(Error),
+    // Not a valid nmap XML output
+    Malformed(String),
+    // Output ends before the run stats
+    Truncated,
+    // nmap reported an error at the end of the run
+    Failed(String),
+}
+
+impl fmt::Display for NmapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NmapError::NotInstalled => write!(f, "nmap is not installed"),
+            NmapError::Io(e) => write!(f, "unable to run nmap: {}", e),
+            NmapError::Malformed(e) => write!(f, "malformed nmap output: {}", e),
+            NmapError::Truncated => write!(f, "truncated nmap output"),
+            NmapError::Failed(e) => write!(f, "nmap failed: {}", e),
+        }
+    }
+}
+
+impl From<Error> for NmapError {
+    fn from(err: Error) -> Self {
+        NmapError::Io(err)
+    }
 }
 
 impl PartialEq for Host {
     fn eq(&self, other: &Host) -> bool {
-        self.address[0].addr == other.address[0].addr
+        self.get_ipv4() == other.get_ipv4()
+    }
+}
+
+impl Host {
+    pub fn is_up(&self) -> bool {
+        self.status.state == "up"
+    }
+
+    pub fn get_ipv4(&self) -> Option<Ipv4Addr> {
+        self.address.iter()
+            .find(|a| a.addrtype == "ipv4")
+            .and_then(|a| a.addr.parse().ok())
+    }
+
+    pub fn get_hostname(&self) -> Option<String> {
+        self.hostnames.hostname.first().map(|h| h.name.to_string())
+    }
+
+    pub fn get_mac(&self) -> Option<MacAddr> {
+        self.address.iter()
+            .find(|a| a.addrtype == "mac")
+            .and_then(|a| MacAddr::from_str(&a.addr).ok())
     }
 }
 
 fn get_unknown() -> String { "Unknown".to_string() }
 
+// Randomized (private) MAC addresses have the locally administered bit set
+pub fn is_locally_administered(mac: &MacAddr) -> bool {
+    mac.0 & 0x02 != 0
+}
+
+// Group addresses (i.e. '01:00:5E:..' of IPv4 multicast) do not belong to a device
+pub fn is_multicast(mac: &MacAddr) -> bool {
+    mac.0 & 0x01 != 0
+}
+
 //ipmask: IP/Mask in String format like '192.168.1.0/24'
-//round: Total round of scan can increase accuracy of result
-pub fn do_scan_nmap(ipmask: &String, round: u8) -> Vec<Host> {
+//interface: Interface to force nmap to use, nmap picks one if None
+//options: Probes and timing arguments, i.e. '-PS22,80 -T4'
+//round: Total round of scan can increase accuracy of result, the rounds run concurrently
+//The error of the last round is returned if none of them succeeded
+pub fn do_scan_nmap(ipmask: &String, interface: Option<&String>, options: &Vec<String>, round: u8) -> Result<Vec<Host>, NmapError> {
     let mut res: Vec<Host> = vec![];
-
-    for _ in 0..round {
-        let file_temp = NamedTempFile::new().expect("Could not create tempfile");
-        let file_temp_path = file_temp
-            .path()
-            .to_str()
-            .expect("Could not find tempfile")
-            .to_string();
-
-        match Command::new("nmap")
-            .arg("-sn")
-            .arg("-PS")
-            .arg(format!("{}", &ipmask))
-            .arg("-oX")
-            .arg(file_temp_path.clone())
-            .stdout(Stdio::null())
-            .output()
-        {
-            Ok(r) => r,
-            Err(ref e) if e.kind() == ErrorKind::NotFound => {
-                exit(ExitCodes::NmapNotInstalled as i32);
-            }
-            Err(_) => {
-                exit(ExitCodes::NmapRunError as i32);
+    let mut succeeded = false;
+    let mut last: Option<NmapError> = None;
+
+    let rounds: Vec<thread::JoinHandle<Result<NmapRun, NmapError>>> = (0..round.max(1))
+        .map(|_| {
+            let ipmask = ipmask.to_string();
+            let interface = interface.cloned();
+            let options = options.clone();
+            thread::spawn(move || run_nmap(&ipmask, interface.as_ref(), &options))
+        })
+        .collect();
+
+    for r in rounds {
+        // A bad output fails only its own round, nmap can not run at all otherwise
+        let result = match r.join() {
+            Ok(Ok(r)) => r,
+            Ok(Err(NmapError::NotInstalled)) => return Err(NmapError::NotInstalled),
+            Ok(Err(NmapError::Io(e))) => return Err(NmapError::Io(e)),
+            Ok(Err(e)) => {
+                warn!("[net::do_scan_nmap()]: round skipped. Err: {}", e);
+                last = Some(e);
+                continue;
             }
+            Err(_) => return Err(NmapError::Failed("round panicked".to_string())),
         };
 
-        let reader = BufReader::new(file_temp.as_file());
-
-        let result: NmapRun = from_reader(reader).unwrap();
+        succeeded = true;
 
         for g in result.hosts {
-            if !res.contains(&g) {
+            if g.is_up() && g.get_ipv4().is_some() && !res.contains(&g) {
                 res.push(g);
             }
         }
     }
 
-    res
+    match last {
+        Some(e) if !succeeded => Err(e),
+        _ => Ok(res),
+    }
 }
 
-pub fn do_scan_arp() -> HashMap<Ipv4Addr, MacAddr> {
-    let path = "/proc/net/arp";
+// Runs a single round, the whole XML output is read from the stdout and then parsed
+fn run_nmap(ipmask: &String, interface: Option<&String>, options: &Vec<String>) -> Result<NmapRun, NmapError> {
+    let mut cmd = Command::new("nmap");
 
-    let mut map: HashMap<Ipv4Addr, MacAddr> = HashMap::new();
+    if let Some(i) = interface {
+        cmd.arg("-e").arg(i);
+    }
 
-    let _f = match OpenOptions::new().read(true).write(false).open(path) {
-        Ok(v) => {
-            let file = BufReader::new(&v);
+    let mut child = match cmd
+        .arg("-sn")
+        .args(options)
+        .arg(format!("{}", &ipmask))
+        .arg("-oX")
+        .arg("-")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+    {
+        Ok(r) => r,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Err(NmapError::NotInstalled),
+        Err(e) => return Err(NmapError::Io(e)),
+    };
 
-            for (num, line) in file.lines().enumerate() {
-                if num == 0 {
-                    continue;
-                }
+    // Piped above
+    let result = parse_nmap(BufReader::new(child.stdout.take().unwrap()));
 
-                let l = line.unwrap();
-                let mut x = l.split_whitespace();
+    if let Err(e) = child.wait() {
+        warn!("[net::run_nmap()]: unable to wait nmap. Err: {}", e);
+    }
 
-                let str_ip: &str = x.nth(0).unwrap();
-                let str_mac: &str = x.nth(2).unwrap();
+    result
+}
 
-                if str_mac == "00:00:00:00:00:00" {
-                    continue;
-                }
+// Parses an nmap XML output, the output must be complete and successful
+pub fn parse_nmap<R: Read>(mut reader: R) -> Result<NmapRun, NmapError> {
+    let mut content = String::new();
+    reader.read_to_string(&mut content)?;
+
+    // The root is closed last, so a killed nmap leaves it open
+    if content.contains("<nmaprun") && !content.trim_end().ends_with("</nmaprun>") {
+        return Err(NmapError::Truncated);
+    }
+
+    let result: NmapRun = from_str(&content).map_err(|e| NmapError::Malformed(e.to_string()))?;
+
+    match result.runstats {
+        None => Err(NmapError::Truncated),
+        Some(ref r) if r.finished.exit == "error" => Err(NmapError::Failed(r.finished.errormsg.to_string())),
+        Some(_) => Ok(result),
+    }
+}
+
+// Reads a saved nmap XML output
+pub fn read_nmap_file(path: &str) -> Result<NmapRun, NmapError> {
+    let file = BufReader::new(OpenOptions::new().read(true).write(false).open(path)?);
+
+    parse_nmap(file)
+}
+
+// Returns the raw ARP table, an IP may appear more than once (i.e. on different devices)
+pub fn do_scan_arp() -> Result<Vec<ArpEntry>, Error> {
+    read_arp_table(ARP_TABLE)
+}
+
+// Reads an ARP table in the '/proc/net/arp' format, i.e. a saved snapshot
+pub fn read_arp_table(path: &str) -> Result<Vec<ArpEntry>, Error> {
+    let mut entries: Vec<ArpEntry> = vec![];
+
+    let file = BufReader::new(OpenOptions::new().read(true).write(false).open(path)?);
+
+    for (num, line) in file.lines().enumerate() {
+        if num == 0 {
+            continue;
+        }
+
+        let l = line?;
+
+        // IP address, HW type, Flags, HW address, Mask, Device
+        let x: Vec<&str> = l.split_whitespace().collect();
+
+        if x.len() < 4 {
+            continue;
+        }
+
+        let str_ip: &str = x[0];
+        let str_mac: &str = x[3];
+
+        if str_mac == "00:00:00:00:00:00" {
+            continue;
+        }
+
+        let (ip, mac) = match (str_ip.parse::<Ipv4Addr>(), MacAddr::from_str(str_mac)) {
+            (Ok(i), Ok(m)) => (i, m),
+            _ => continue,
+        };
 
-                let ip: Ipv4Addr = str_ip.parse().unwrap();
-                let mac: MacAddr = MacAddr::from_str(str_mac).unwrap();
+        entries.push(ArpEntry {
+            ip: ip,
+            mac: mac,
+            device: x.get(5).unwrap_or(&"").to_string(),
+        });
+    }
+
+    Ok(entries)
+}
 
-                map.insert(ip, mac);
+pub fn get_arp_map(entries: &Vec<ArpEntry>) -> HashMap<Ipv4Addr, MacAddr> {
+    let mut map: HashMap<Ipv4Addr, MacAddr> = HashMap::new();
+
+    for entry in entries {
+        map.insert(entry.ip, entry.mac);
+    }
+
+    map
+}
+
+// Address of the interface in the network, the interface must be up
+pub fn get_interface_addr(interface: &str, network: &ScanInfo) -> Result<Ipv4Addr, Error> {
+    let iface = match datalink::interfaces().into_iter().find(|i| i.name == interface) {
+        Some(r) => r,
+        None => return Err(Error::new(ErrorKind::NotFound, "no such interface")),
+    };
+
+    if !iface.is_up() {
+        return Err(Error::new(ErrorKind::Other, "interface is down"));
+    }
+
+    for ip in &iface.ips {
+        if let IpAddr::V4(addr) = ip.ip() {
+            if network.contains(&addr) {
+                return Ok(addr);
             }
+        }
+    }
+
+    Err(Error::new(ErrorKind::NotFound, format!("interface has no address in {}", network)))
+}
 
-            return map;
+// Interface those owns an address in the network and the address itself
+pub fn get_local_addr(network: &ScanInfo) -> Result<(String, Ipv4Addr), Error> {
+    for iface in datalink::interfaces() {
+        for ip in &iface.ips {
+            if let IpAddr::V4(addr) = ip.ip() {
+                if !addr.is_loopback() && network.contains(&addr) {
+                    return Ok((iface.name.to_string(), addr));
+                }
+            }
         }
-        Err(e) => panic!("Unable to read mac address from {}, Err: {}", path, e),
+    }
+
+    Err(Error::new(ErrorKind::NotFound, format!("no interface has an address in {}", network)))
+}
+
+// Default route of the interface from '/proc/net/route', MAC from the ARP cache
+pub fn get_gateway(interface: &str, local: Ipv4Addr) -> Result<Gateway, Error> {
+    let ip = match read_default_route(ROUTE_TABLE, Some(interface))? {
+        Some(r) => r,
+        None => return Err(Error::new(ErrorKind::NotFound, format!("no default route through {}", interface))),
     };
+
+    let mut mac = find_arp_mac(&ip)?;
+
+    // Trigger the kernel to resolve it if the gateway is not in the ARP cache yet
+    if mac.is_none() {
+        let socket = UdpSocket::bind((local, 0))?;
+        let _ = socket.send_to(&[], (ip, 9));
+        thread::sleep(Duration::from_millis(500));
+        mac = find_arp_mac(&ip)?;
+    }
+
+    match mac {
+        Some(m) => Ok(Gateway { ip: ip, mac: m }),
+        None => {
+            warn!("[net::get_gateway()]: unable to resolve the MAC of the gateway {}", ip);
+            Ok(Gateway { ip: ip, mac: MacAddr::zero() })
+        }
+    }
 }
 
-pub fn get_hostname_addr() -> Result<Ipv4Addr, Error> {
-    let output = match Command::new("hostname").arg("-i").output() {
-        Ok(r) => r,
-        Err(ref e) if e.kind() == ErrorKind::NotFound => {
-            error!("Hostname command not found!");
-            exit(ExitCodes::HostnameNotFound as i32);
+// Reads a routing table in the '/proc/net/route' format, i.e. a saved snapshot
+pub fn read_default_route(path: &str, interface: Option<&str>) -> Result<Option<Ipv4Addr>, Error> {
+    let file = OpenOptions::new().read(true).write(false).open(path)?;
+
+    parse_default_route(BufReader::new(file), interface)
+}
+
+// Gateway of the first default route, only the routes of the interface are
+// looked up if it is given
+pub fn parse_default_route<R: BufRead>(reader: R, interface: Option<&str>) -> Result<Option<Ipv4Addr>, Error> {
+    // Iface, Destination, Gateway, Flags, RefCnt, Use, Metric, Mask, MTU, Window, IRTT
+    for line in reader.lines().skip(1) {
+        let l = line?;
+        let x: Vec<&str> = l.split_whitespace().collect();
+
+        if x.len() < 4 || interface.map_or(false, |i| x[0] != i) || x[1] != "00000000" {
+            continue;
         }
-        Err(e) => {
-            error!("Hostname command run error: {}", e);
-            exit(ExitCodes::HostnameRunError as i32);
+
+        let flags = u16::from_str_radix(x[3], 16).unwrap_or(0);
+        if flags & RTF_GATEWAY == 0 {
+            continue;
         }
-    };
 
-    let ip: Ipv4Addr = match String::from_utf8_lossy(&output.stdout).trim().parse() {
-        Ok(r) => r,
-        Err(e) => {
-            error!("Hostname output parse error: {}", e);
-            exit(ExitCodes::HostnameParseError as i32);
+        // The kernel prints the address in the host byte order
+        if let Ok(gw) = u32::from_str_radix(x[2], 16) {
+            return Ok(Some(Ipv4Addr::from(gw.to_ne_bytes())));
         }
-    };
-    Ok(ip)
+    }
+
+    Ok(None)
 }
 
-pub fn get_gateway() -> Gateway {
-    lazy_static! {
-        static ref RGX_MAC: Regex = Regex::new(r"([0-9a-fA-F]{1,2}[\.:-]){5}([0-9a-fA-F]{1,2})").unwrap();
-        static ref RGX_IP: Regex = Regex::new(r"((?:[0-9]{1,3}\.){3}[0-9]{1,3})").unwrap();
+fn find_arp_mac(ip: &Ipv4Addr) -> Result<Option<MacAddr>, Error> {
+    Ok(read_arp_table(ARP_TABLE)?.into_iter().find(|e| e.ip == *ip).map(|e| e.mac))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> Result<NmapRun, NmapError> {
+        parse_nmap(content.as_bytes())
+    }
+
+    #[test]
+    fn parse_nmap_reads_the_hosts() {
+        let run = parse(include_str!("../data/fixtures/nmap/scan.xml")).unwrap();
+
+        assert_eq!(run.version, "7.70");
+        assert_eq!(run.hosts.len(), 4);
+
+        let router = &run.hosts[0];
+        assert!(router.is_up());
+        assert_eq!(router.get_ipv4(), Some(Ipv4Addr::new(192, 168, 1, 1)));
+        assert_eq!(router.get_mac(), Some(MacAddr::new(0x50, 0xc7, 0xbf, 0x00, 0x11, 0x22)));
+        assert_eq!(router.get_hostname(), Some("router.lan".to_string()));
+        assert_eq!(router.address[1].vendor, "Tp-link Technologies");
+    }
+
+    #[test]
+    fn parse_nmap_reads_the_addresses_in_any_order() {
+        let run = parse(include_str!("../data/fixtures/nmap/scan.xml")).unwrap();
+
+        // The MAC is written before the IPv4 address, and it has two hostnames
+        let phone = &run.hosts[1];
+        assert_eq!(phone.get_ipv4(), Some(Ipv4Addr::new(192, 168, 1, 20)));
+        assert_eq!(phone.get_mac(), Some(MacAddr::new(0x01, 0x23, 0x45, 0x67, 0x89, 0xab)));
+        assert_eq!(phone.hostnames.hostname.len(), 2);
+        assert_eq!(phone.get_hostname(), Some("Furkans-iPhone.lan".to_string()));
+
+        // Not run as root, so there is no MAC
+        let host = &run.hosts[3];
+        assert_eq!(host.get_ipv4(), Some(Ipv4Addr::new(192, 168, 1, 30)));
+        assert_eq!(host.get_mac(), None);
+        assert_eq!(host.get_hostname(), None);
+    }
+
+    #[test]
+    fn parse_nmap_reads_the_down_hosts() {
+        let run = parse(include_str!("../data/fixtures/nmap/scan.xml")).unwrap();
+
+        let down: Vec<&Host> = run.hosts.iter().filter(|h| !h.is_up()).collect();
+        assert_eq!(down.len(), 1);
+        assert_eq!(down[0].get_ipv4(), Some(Ipv4Addr::new(192, 168, 1, 21)));
     }
 
-    let output = Command::new("arp")
-        .arg("-a")
-        .arg("_gateway")
-        .output()
-        .expect("failed to execute process");
+    #[test]
+    fn parse_nmap_reads_the_runstats() {
+        let run = parse(include_str!("../data/fixtures/nmap/scan.xml")).unwrap();
+
+        let stats = run.runstats.unwrap();
+        assert_eq!(stats.finished.exit, "success");
+        assert_eq!(stats.hosts, HostStats { up: 3, down: 253, total: 256 });
 
-    let f = String::from_utf8(output.stdout).unwrap();
+        let run = parse(include_str!("../data/fixtures/nmap/empty.xml")).unwrap();
+        assert!(run.hosts.is_empty());
+        assert_eq!(run.runstats.unwrap().hosts.total, 4);
+    }
 
-    if output.status.success() {
-        let ip = RGX_IP.find(&f).unwrap().as_str().parse().unwrap();
-        let mac = RGX_MAC.find(&f).unwrap().as_str().parse().unwrap();
+    #[test]
+    fn parse_nmap_fails_on_the_reported_error() {
+        match parse(include_str!("../data/fixtures/nmap/error.xml")) {
+            Err(NmapError::Failed(e)) => assert_eq!(e, "Unable to find interface eth9"),
+            r => panic!("unexpected result: {:?}", r.map(|r| r.hosts.len())),
+        }
+    }
+
+    #[test]
+    fn parse_nmap_fails_on_the_truncated_output() {
+        match parse(include_str!("../data/fixtures/nmap/truncated.xml")) {
+            Err(NmapError::Truncated) => {}
+            r => panic!("unexpected result: {:?}", r.map(|r| r.hosts.len())),
+        }
+
+        // The root is closed but the run stats are missing
+        match parse(include_str!("../data/fixtures/nmap/no_runstats.xml")) {
+            Err(NmapError::Truncated) => {}
+            r => panic!("unexpected result: {:?}", r.map(|r| r.hosts.len())),
+        }
+    }
 
-        let gw: Gateway = Gateway { ip: ip, mac: mac };
+    #[test]
+    fn parse_nmap_fails_on_the_non_xml_output() {
+        for content in &["", "Starting Nmap 7.70 ( https://nmap.org )\nFailed to resolve \"192.168.1.0/33\".\n", "<html><body>nope</body></html>"] {
+            match parse(content) {
+                Err(NmapError::Malformed(_)) => {}
+                r => panic!("unexpected result for {:?}: {:?}", content, r.map(|r| r.hosts.len())),
+            }
+        }
+    }
+
+    // 'route -n' of this table: default via 192.168.1.1 on eth0, 10.0.0.1 on wlan0
+    const ROUTES: &str = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+        eth0\t0001A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0\n\
+        eth0\t00000000\t0101A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0\n\
+        wlan0\t00000000\t0100000A\t0003\t0\t0\t600\t00000000\t0\t0\t0\n\
+        eth1\t00000000\t00000000\t0001\t0\t0\t100\t00000000\t0\t0\t0\n";
+
+    fn host_order(ip: Ipv4Addr) -> String {
+        format!("{:08X}", u32::from_ne_bytes(ip.octets()))
+    }
+
+    #[test]
+    fn parse_default_route_reads_the_gateway() {
+        // Only little-endian hosts print the table above
+        if cfg!(target_endian = "little") {
+            assert_eq!(parse_default_route(ROUTES.as_bytes(), Some("eth0")).unwrap(), Some(Ipv4Addr::new(192, 168, 1, 1)));
+            assert_eq!(parse_default_route(ROUTES.as_bytes(), Some("wlan0")).unwrap(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+            assert_eq!(parse_default_route(ROUTES.as_bytes(), None).unwrap(), Some(Ipv4Addr::new(192, 168, 1, 1)));
+        }
+
+        // Written like the kernel of this host does
+        let gateway = Ipv4Addr::new(172, 16, 0, 254);
+        let table = format!("Iface\tDestination\tGateway \tFlags\neth0\t00000000\t{}\t0003\n", host_order(gateway));
+        assert_eq!(parse_default_route(table.as_bytes(), Some("eth0")).unwrap(), Some(gateway));
+    }
 
-        return gw;
-    } else {
-        let err = String::from_utf8_lossy(&output.stderr);
-        panic!("Unable to get gw! Err: {}", err);
+    #[test]
+    fn parse_default_route_skips_the_other_routes() {
+        // Local subnet only, and a default route without the gateway flag
+        assert_eq!(parse_default_route(ROUTES.as_bytes(), Some("eth1")).unwrap(), None);
+        assert_eq!(parse_default_route(ROUTES.as_bytes(), Some("eth9")).unwrap(), None);
+        assert_eq!(parse_default_route("Iface\tDestination\tGateway \tFlags\n".as_bytes(), None).unwrap(), None);
     }
 }
@@ -0,0 +1,245 @@
+// ====================================================
+// Netlyser Copyright(C) 2019 Furkan Türkal
+// This program comes with ABSOLUTELY NO WARRANTY; This is free software,
+// and you are welcome to redistribute it under certain conditions; See
+// file LICENSE, which is part of this source code package, for details.
+// ====================================================
+
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{BufRead, BufReader, Error, ErrorKind},
+    path::Path,
+    process::Command,
+};
+
+use pnet::util::MacAddr;
+
+use crate::net;
+
+const EMBEDDED: &str = include_str!("../data/oui.txt");
+
+// The IEEE MA-L registry, the source of 'oui update'
+pub const IEEE_URL: &str = "https://standards-oui.ieee.org/oui/oui.txt";
+
+// The registry has tens of thousands of vendors, a smaller download is an error page
+const MIN_VENDORS: usize = 10000;
+
+// Well known locations of the full vendor databases
+const SYSTEM_FILES: [&str; 3] = [
+    "/usr/share/nmap/nmap-mac-prefixes",
+    "/usr/share/ieee-data/oui.txt",
+    "/usr/share/misc/oui.txt",
+];
+
+#[derive(Debug, Clone, Default)]
+pub struct OuiDb {
+    vendors: HashMap<[u8; 3], String>,
+}
+
+impl OuiDb {
+    pub fn embedded() -> OuiDb {
+        let mut db = OuiDb::default();
+        for line in EMBEDDED.lines() {
+            db.add_line(line);
+        }
+        db
+    }
+
+    // Embedded fallback list extended by the given file, or by the first system file found.
+    // Without one most of the MACs are left unknown
+    pub fn new(path: &Option<String>) -> Result<OuiDb, Error> {
+        let mut db = OuiDb::embedded();
+
+        match path {
+            Some(p) => db.load(p)?,
+            None => match SYSTEM_FILES.iter().find(|p| Path::new(p).exists()) {
+                Some(p) => db.load(p)?,
+                None => warn!("[oui::new()]: no OUI file is found, only the {} fallback vendors are resolved. Set 'general.oui_file' and run 'netlyser oui update'", db.len()),
+            },
+        }
+
+        Ok(db)
+    }
+
+    // Supports nmap 'nmap-mac-prefixes', IEEE 'oui.txt' / 'oui.csv' and Wireshark 'manuf' formats
+    pub fn load(&mut self, path: &str) -> Result<(), Error> {
+        let file = OpenOptions::new().read(true).write(false).open(path)?;
+
+        let before = self.vendors.len();
+
+        for line in BufReader::new(file).lines() {
+            self.add_line(&line?);
+        }
+
+        info!("[oui::load()]: {} vendors loaded from {}", self.vendors.len() - before, path);
+
+        Ok(())
+    }
+
+    pub fn lookup(&self, mac: &MacAddr) -> Option<&str> {
+        if net::is_locally_administered(mac) || net::is_multicast(mac) {
+            return None;
+        }
+        self.vendors.get(&[mac.0, mac.1, mac.2]).map(|v| v.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.vendors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vendors.is_empty()
+    }
+
+    fn add_line(&mut self, line: &str) {
+        if let Some((prefix, vendor)) = parse_line(line) {
+            self.vendors.insert(prefix, vendor);
+        }
+    }
+}
+
+// Downloads the IEEE registry to the path, the old file is kept if the download
+// fails or is not a vendor database. Returns the number of the vendors
+pub fn update(path: &str, url: &str) -> Result<usize, Error> {
+    let temp = format!("{}.download", path);
+
+    let status = match Command::new("curl").arg("-fsSL").arg("-o").arg(&temp).arg(url).status() {
+        Ok(r) => r,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Err(Error::new(ErrorKind::NotFound, "'curl' is not installed")),
+        Err(e) => return Err(e),
+    };
+
+    if !status.success() {
+        let _ = fs::remove_file(&temp);
+        return Err(Error::new(ErrorKind::Other, format!("unable to download {}: curl exited with {}", url, status)));
+    }
+
+    let mut db = OuiDb::default();
+    if let Err(e) = db.load(&temp) {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+
+    if db.len() < MIN_VENDORS {
+        let _ = fs::remove_file(&temp);
+        return Err(Error::new(ErrorKind::InvalidData, format!("{} has only {} vendors, it is not an OUI registry", url, db.len())));
+    }
+
+    fs::rename(&temp, path)?;
+
+    Ok(db.len())
+}
+
+fn parse_prefix(hex: &str) -> Option<[u8; 3]> {
+    let digits: String = hex.chars().filter(|c| *c != '-' && *c != ':').collect();
+
+    if digits.len() != 6 {
+        return None;
+    }
+
+    let mut prefix = [0u8; 3];
+    for (i, b) in prefix.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16).ok()?;
+    }
+
+    Some(prefix)
+}
+
+pub fn parse_line(line: &str) -> Option<([u8; 3], String)> {
+    let line = line.trim();
+
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+
+    // IEEE oui.txt: '00-00-0C   (hex)		Cisco Systems, Inc'
+    if let Some(pos) = line.find("(hex)") {
+        let prefix = parse_prefix(line[..pos].trim())?;
+        return Some((prefix, line[pos + 5..].trim().to_string()));
+    }
+
+    // IEEE oui.csv: 'MA-L,00000C,"Cisco Systems, Inc",...'
+    if line.starts_with("MA-L,") {
+        let rest = &line[5..];
+        let prefix = parse_prefix(rest.get(..6)?)?;
+        let vendor = rest.get(7..)?;
+        let vendor = if vendor.starts_with('"') {
+            vendor[1..].split('"').next()?
+        } else {
+            vendor.split(',').next()?
+        };
+        return Some((prefix, vendor.trim().to_string()));
+    }
+
+    // nmap-mac-prefixes: '00000C Cisco Systems'
+    // manuf            : '00:00:0C	Cisco	Cisco Systems, Inc'
+    let mut parts = line.splitn(2, char::is_whitespace);
+    let prefix = parse_prefix(parts.next()?)?;
+    let vendor = parts.next()?.trim();
+
+    if vendor.is_empty() {
+        return None;
+    }
+
+    // Prefer the long name of the manuf format
+    let vendor = vendor.rsplit('\t').next().unwrap_or(vendor).trim();
+
+    Some((prefix, vendor.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    fn mac(s: &str) -> MacAddr {
+        MacAddr::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn lookup_finds_the_embedded_vendors() {
+        let db = OuiDb::embedded();
+
+        assert!(!db.is_empty());
+        assert_eq!(db.lookup(&mac("00:03:93:12:34:56")), Some("Apple"));
+        assert_eq!(db.lookup(&mac("00:12:47:12:34:56")), Some("Samsung Electronics"));
+        assert_eq!(db.lookup(&mac("00:00:00:00:00:01")), None);
+    }
+
+    #[test]
+    fn lookup_ignores_local_and_group_addresses() {
+        let mut db = OuiDb::embedded();
+        db.add_line("020393 Random Vendor");
+        db.add_line("01005E IANA Multicast");
+
+        // Randomized address of a phone, even if the prefix is registered
+        assert_eq!(db.lookup(&mac("02:03:93:12:34:56")), None);
+        // IPv4 multicast, IPv6 multicast and broadcast
+        assert_eq!(db.lookup(&mac("01:00:5e:00:00:fb")), None);
+        assert_eq!(db.lookup(&mac("33:33:00:00:00:fb")), None);
+        assert_eq!(db.lookup(&mac("ff:ff:ff:ff:ff:ff")), None);
+    }
+
+    #[test]
+    fn load_reads_every_format() {
+        let mut db = OuiDb::default();
+        db.load("data/fixtures/oui/vendors.txt").unwrap();
+
+        assert_eq!(db.len(), 4);
+        assert_eq!(db.lookup(&mac("00:00:0c:12:34:56")), Some("Cisco Systems, Inc"));
+        assert_eq!(db.lookup(&mac("00:1b:63:12:34:56")), Some("Apple, Inc."));
+        assert_eq!(db.lookup(&mac("50:c7:bf:12:34:56")), Some("TP-LINK TECHNOLOGIES CO.,LTD."));
+        assert_eq!(db.lookup(&mac("b8:27:eb:12:34:56")), Some("Raspberry Pi Foundation"));
+    }
+
+    #[test]
+    fn new_extends_the_embedded_vendors() {
+        let db = OuiDb::new(&Some("data/fixtures/oui/vendors.txt".to_string())).unwrap();
+
+        assert_eq!(db.lookup(&mac("00:12:47:12:34:56")), Some("Samsung Electronics"));
+        assert_eq!(db.lookup(&mac("00:00:0c:12:34:56")), Some("Cisco Systems, Inc"));
+        assert!(OuiDb::new(&Some("data/fixtures/oui/missing.txt".to_string())).is_err());
+    }
+}
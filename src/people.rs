@@ -0,0 +1,167 @@
+// ====================================================
+// Netlyser Copyright(C) 2019 Furkan Türkal
+// This program comes with ABSOLUTELY NO WARRANTY; This is free software,
+// and you are welcome to redistribute it under certain conditions; See
+// file LICENSE, which is part of this source code package, for details.
+// ====================================================
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::db;
+use crate::monitor::Event;
+
+// Person level presence over every network and interface, a person may be
+// seen by one scanner while the others report nothing
+#[derive(Debug, Default)]
+pub struct Tracker {
+    // Present hosts by the network label and the interface
+    hosts: HashMap<(String, String), Vec<db::Host>>,
+    home: BTreeMap<String, db::Person>,
+}
+
+impl Tracker {
+    pub fn new() -> Tracker {
+        Tracker::default()
+    }
+
+    // Sets the hosts without reporting the changes, i.e. the restored presence
+    pub fn seed(&mut self, network: &str, interface: &str, hosts: &Vec<db::Host>) {
+        self.hosts.insert((network.to_string(), interface.to_string()), hosts.clone());
+        self.home = self.get_home();
+    }
+
+    // Drops the hosts of a scanner those thread is exited, the departures are
+    // reported by the next update of the others
+    pub fn remove(&mut self, network: &str, interface: &str) {
+        self.hosts.remove(&(network.to_string(), interface.to_string()));
+    }
+
+    // Returns the arrivals and the departures caused by the hosts of the scanner
+    pub fn update(&mut self, network: &str, interface: &str, hosts: &Vec<db::Host>) -> Vec<Event> {
+        self.hosts.insert((network.to_string(), interface.to_string()), hosts.clone());
+
+        let home = self.get_home();
+
+        let mut events: Vec<Event> = vec![];
+
+        for (name, person) in &self.home {
+            if !home.contains_key(name) {
+                info!("[people::update()]: {} left", name);
+                events.push(Event::Departed(person.clone()));
+            }
+        }
+
+        for (name, person) in &home {
+            if !self.home.contains_key(name) {
+                info!("[people::update()]: {} arrived", name);
+                events.push(Event::Arrived(person.clone()));
+            }
+        }
+
+        self.home = home;
+
+        events
+    }
+
+    fn get_home(&self) -> BTreeMap<String, db::Person> {
+        let mut home: BTreeMap<String, db::Person> = BTreeMap::new();
+
+        for host in self.hosts.values().flatten().filter(|h| h.primary) {
+            let name = match host.person {
+                Some(ref r) => r,
+                None => continue,
+            };
+
+            let person = home.entry(name.to_string()).or_insert_with(|| db::Person {
+                name: name.to_string(),
+                devices: vec![],
+            });
+
+            // Multi-homed scanners see the same device
+            if !person.devices.iter().any(|d| d.mac == host.mac && d.ip == host.ip) {
+                person.devices.push(host.clone());
+            }
+        }
+
+        home
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use pnet::util::MacAddr;
+
+    fn get_host(mac: &str, person: Option<&str>, primary: bool) -> db::Host {
+        let mut h = db::Host::new();
+        h.set_ip("192.168.1.20".parse().unwrap());
+        h.set_mac(MacAddr::from_str(mac).unwrap());
+        h.person = person.map(String::from);
+        h.primary = primary;
+        h
+    }
+
+    fn names(events: &Vec<Event>) -> Vec<String> {
+        events.iter().map(|e| match e {
+            Event::Arrived(p) => format!("+{}", p.name),
+            Event::Departed(p) => format!("-{}", p.name),
+            e => panic!("unexpected event {:?}", e),
+        }).collect()
+    }
+
+    #[test]
+    fn update_reports_arrivals_and_departures() {
+        let mut tracker = Tracker::new();
+        let phone = get_host("02:00:00:00:00:20", Some("Furkan"), true);
+
+        assert_eq!(names(&tracker.update("home", "eth0", &vec![phone.clone()])), vec!["+Furkan"]);
+        assert!(tracker.update("home", "eth0", &vec![phone.clone()]).is_empty());
+        assert_eq!(names(&tracker.update("home", "eth0", &vec![])), vec!["-Furkan"]);
+    }
+
+    #[test]
+    fn update_ignores_the_non_primary_devices() {
+        let mut tracker = Tracker::new();
+        let phone = get_host("02:00:00:00:00:20", Some("Furkan"), true);
+        let laptop = get_host("02:00:00:00:00:21", Some("Furkan"), false);
+        let tv = get_host("02:00:00:00:00:22", None, true);
+
+        assert!(tracker.update("home", "eth0", &vec![laptop.clone(), tv.clone()]).is_empty());
+        assert_eq!(names(&tracker.update("home", "eth0", &vec![laptop.clone(), phone.clone()])), vec!["+Furkan"]);
+
+        // The laptop alone does not keep the person home
+        assert_eq!(names(&tracker.update("home", "eth0", &vec![laptop.clone()])), vec!["-Furkan"]);
+    }
+
+    #[test]
+    fn update_merges_the_interfaces() {
+        let mut tracker = Tracker::new();
+        let phone = get_host("02:00:00:00:00:20", Some("Furkan"), true);
+
+        assert_eq!(names(&tracker.update("home", "eth0", &vec![phone.clone()])), vec!["+Furkan"]);
+        assert!(tracker.update("home", "wlan0", &vec![phone.clone()]).is_empty());
+
+        // Seen twice, listed once
+        match tracker.home.get("Furkan") {
+            Some(p) => assert_eq!(p.devices.len(), 1),
+            None => panic!("Furkan is not home"),
+        }
+
+        assert!(tracker.update("home", "eth0", &vec![]).is_empty());
+        assert_eq!(names(&tracker.update("home", "wlan0", &vec![])), vec!["-Furkan"]);
+    }
+
+    #[test]
+    fn remove_forgets_an_exited_scanner() {
+        let mut tracker = Tracker::new();
+        let phone = get_host("02:00:00:00:00:20", Some("Furkan"), true);
+
+        tracker.update("home", "eth0", &vec![phone.clone()]);
+        tracker.remove("home", "eth0");
+
+        assert_eq!(names(&tracker.update("home", "wlan0", &vec![])), vec!["-Furkan"]);
+    }
+}
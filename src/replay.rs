@@ -0,0 +1,256 @@
+// ====================================================
+// Netlyser Copyright(C) 2019 Furkan Türkal
+// This program comes with ABSOLUTELY NO WARRANTY; This is free software,
+// and you are welcome to redistribute it under certain conditions; See
+// file LICENSE, which is part of this source code package, for details.
+// ====================================================
+
+use std::{
+    env,
+    fs,
+    net::Ipv4Addr,
+    path::Path,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, TryRecvError},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use pnet::util::MacAddr;
+
+use chrono::prelude::*;
+
+use crate::config;
+use crate::db;
+use crate::error::Result;
+use crate::monitor::{self, Event, Pipeline, Present, Record, ScanResult, ScanState, Sinks};
+use crate::net;
+use crate::oui;
+use crate::people;
+
+pub enum Step {
+    // Default gateway, if there is one
+    Route(Option<Ipv4Addr>),
+    Arp(Vec<net::ArpEntry>),
+    Scan(Vec<net::Host>),
+}
+
+// Recordings named by their unix timestamp, in the order they are taken:
+// * '<timestamp>.xml': nmap XML output
+// * '<timestamp>.arp': ARP table snapshot in the '/proc/net/arp' format
+// * '<timestamp>.route': routing table snapshot in the '/proc/net/route' format
+// The snapshots are used by the scans of the same and the later timestamps
+pub fn read_steps(dir: &str) -> Result<Vec<(i64, Step)>> {
+    let mut steps: Vec<(i64, Step)> = vec![];
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        let time = match path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse::<i64>().ok()) {
+            Some(r) => r,
+            None => {
+                warn!("[replay::read_steps()]: skipped {}, the name is not a timestamp", path.display());
+                continue;
+            }
+        };
+
+        let file = path.to_string_lossy().to_string();
+
+        match path.extension().and_then(|s| s.to_str()) {
+            Some("xml") => match net::read_nmap_file(&file) {
+                Ok(r) => steps.push((time, Step::Scan(r.hosts.into_iter().filter(|h| h.is_up()).collect()))),
+                Err(e) => warn!("[replay::read_steps()]: skipped {}. Err: {}", path.display(), e),
+            },
+            Some("arp") => steps.push((time, Step::Arp(net::read_arp_table(&file)?))),
+            Some("route") => steps.push((time, Step::Route(net::read_default_route(&file, None)?))),
+            _ => warn!("[replay::read_steps()]: skipped {}, unknown extension", path.display()),
+        }
+    }
+
+    steps.sort_by_key(|(time, step)| (*time, match step { Step::Route(_) => 0, Step::Arp(_) => 1, Step::Scan(_) => 2 }));
+
+    Ok(steps)
+}
+
+// Runs the recordings through the same pipeline as the monitor, stamped with
+// their own time. The events and the alerts are stored to the database, to a
+// new one in the temp directory if it is not given, the notifications are only printed
+pub fn replay(dir: &str, conf: &config::Config, db: &String) -> Result<()> {
+    let steps = read_steps(dir)?;
+
+    let db = if db.is_empty() {
+        env::temp_dir().join(format!("netlyser-replay-{}.db", Local::now().timestamp())).to_string_lossy().to_string()
+    } else {
+        db.to_string()
+    };
+
+    db::init_db(&db)?;
+
+    let mut conf = conf.clone();
+    conf.dry_run = true;
+
+    let shared = monitor::share(conf.clone());
+    let live = monitor::current(&shared);
+
+    let ouidb = match oui::OuiDb::new(&conf.general.oui_file) {
+        Ok(r) => r,
+        Err(e) => {
+            warn!("[replay::replay()]: unable to load the OUI file, only the embedded fallback vendors are resolved, run 'netlyser oui update'. Err: {}", e);
+            oui::OuiDb::embedded()
+        }
+    };
+
+    // The notifications are printed after every scan, in the order of the
+    // steps. There is no one to stream the events to
+    let (notifier, notifications) = mpsc::sync_channel::<Event>(monitor::QUEUE_SIZE);
+    let (events, _) = mpsc::sync_channel::<Event>(0);
+
+    let (store, writer) = monitor::spawn_store(&db, shared, Sinks {
+        notifier: notifier,
+        events: events,
+    });
+
+    let pipeline = Pipeline {
+        ouidb: Arc::new(ouidb),
+        people: Arc::new(Mutex::new(people::Tracker::new())),
+        present: Arc::new(Mutex::new(Present::default())),
+        store: store,
+    };
+
+    // The recordings are a single stream, whatever network they are taken on.
+    // There is no live network to find the local address
+    let mut state = ScanState::new("replay", "", Ipv4Addr::UNSPECIFIED, None, vec![]);
+    let mut arps: Vec<net::ArpEntry> = vec![];
+
+    println!("Replaying {} recording(s) from {} into {}", steps.len(), Path::new(dir).display(), db);
+
+    for (time, step) in steps {
+        let stamp = Local.timestamp(time, 0).format("%Y-%m-%d %H:%M:%S");
+
+        match step {
+            Step::Route(gateway) => {
+                match gateway {
+                    Some(ip) => println!("[{}] Route snapshot with the gateway {}", stamp, ip),
+                    None => println!("[{}] Route snapshot without a default route", stamp),
+                }
+
+                // The MAC is learned from the ARP snapshots
+                state.gateway = gateway.map(|ip| net::Gateway { ip: ip, mac: MacAddr::zero() });
+            }
+            Step::Arp(entries) => {
+                println!("[{}] ARP snapshot with {} entries", stamp, entries.len());
+
+                arps = entries;
+            }
+            Step::Scan(hosts) => {
+                println!("[{}] Scan with {} hosts", stamp, hosts.len());
+
+                let result = ScanResult {
+                    source: db::Source::Nmap,
+                    hosts: hosts,
+                    arps: arps.clone(),
+                    passives: vec![],
+                    time: time,
+                };
+
+                monitor::process(&mut state, &pipeline, &live, result);
+
+                print_notifications(&pipeline, &notifications, &conf);
+            }
+        }
+    }
+
+    // Waits for the store to write the queued events
+    drop(pipeline);
+    let _ = writer.join();
+
+    println!("Replay is stored to {}", db);
+
+    Ok(())
+}
+
+// Prints the notifications of the scans those are sent to the store, it is
+// drained while waiting so a long scan does not fill the queue
+fn print_notifications(pipeline: &Pipeline, notifications: &Receiver<Event>, conf: &config::Config) {
+    let (done_tx, done_rx) = mpsc::channel::<()>();
+
+    if pipeline.store.send(Record::Flush(done_tx)).is_err() {
+        return;
+    }
+
+    loop {
+        match notifications.recv_timeout(Duration::from_millis(10)) {
+            Ok(e) => db::notify_event(&e, conf),
+            Err(RecvTimeoutError::Timeout) => match done_rx.try_recv() {
+                Err(TryRecvError::Empty) => {}
+                _ => break,
+            },
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    for e in notifications.try_iter() {
+        db::notify_event(&e, conf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::process;
+
+    use crate::serde_yaml;
+
+    const DIR: &str = "data/fixtures/replay";
+
+    fn get_config() -> config::Config {
+        let content = "general:\n  interval: 1000\n  round: 1\n  notify_on_connect: true\n  notify_on_disconnect: true\nhosts: []\n";
+        serde_yaml::from_str(content).unwrap()
+    }
+
+    #[test]
+    fn read_steps_orders_the_snapshots_before_the_scan() {
+        let steps = read_steps(DIR).unwrap();
+        let kinds: Vec<(i64, &str)> = steps.iter().map(|(time, step)| (*time, match step {
+            Step::Route(_) => "route",
+            Step::Arp(_) => "arp",
+            Step::Scan(_) => "scan",
+        })).collect();
+
+        assert_eq!(kinds, vec![
+            (1557093046, "route"), (1557093046, "arp"), (1557093046, "scan"),
+            (1557093346, "arp"), (1557093346, "scan"),
+        ]);
+
+        match steps[0].1 {
+            Step::Route(ref r) => assert_eq!(*r, Some(Ipv4Addr::new(192, 168, 1, 1))),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn replay_stamps_the_rows_with_the_recording_time() {
+        let db = env::temp_dir().join(format!("netlyser-replay-test-{}.db", process::id())).to_string_lossy().to_string();
+        let _ = fs::remove_file(&db);
+
+        replay(DIR, &get_config(), &db).unwrap();
+
+        let conn = db::open_db(&db).unwrap();
+        let logs = db::get_logs(&conn, None, 100).unwrap();
+        assert!(!logs.is_empty());
+        assert!(logs.iter().all(|l| l.time == 1557093046 || l.time == 1557093346));
+
+        // The gateway is taken from the route snapshot
+        let gateway: Vec<&db::Log> = logs.iter().filter(|l| l.ip == "192.168.1.1").collect();
+        assert!(!gateway.is_empty());
+        assert!(gateway.iter().all(|l| l.name == "GATEWAY"));
+
+        let alerts = db::get_pending_alerts(&conn, None).unwrap();
+        let gateway: Vec<&db::Alert> = alerts.iter().filter(|a| a.kind == "gateway_changed").collect();
+        assert_eq!(gateway.len(), 1);
+        assert_eq!(gateway[0].time, 1557093346);
+        assert_eq!(gateway[0].mac, "02:00:00:00:66:66");
+    }
+}
@@ -0,0 +1,372 @@
+// ====================================================
+// Netlyser Copyright(C) 2019 Furkan Türkal
+// This program comes with ABSOLUTELY NO WARRANTY; This is free software,
+// and you are welcome to redistribute it under certain conditions; See
+// file LICENSE, which is part of this source code package, for details.
+// ====================================================
+
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    process::Command,
+    thread,
+    time::{Duration, Instant},
+};
+
+const MDNS_PORT: u16 = 5353;
+// Lookups those run at the same time, each one may wait for the mDNS timeout
+const MAX_QUERIES: usize = 32;
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const CLASS_IN: u16 = 1;
+
+// Caches the resolved names (and the failures) to not query them on every scan
+pub struct Resolver {
+    cache: HashMap<Ipv4Addr, (Option<String>, Instant)>,
+    ttl: Duration,
+    mdns_timeout: Duration,
+}
+
+impl Resolver {
+    pub fn new(ttl: Duration, mdns_timeout: Duration) -> Resolver {
+        Resolver {
+            cache: HashMap::new(),
+            ttl: ttl,
+            mdns_timeout: mdns_timeout,
+        }
+    }
+
+    // PTR record from the system resolver first, then the '.local' name over mDNS
+    pub fn resolve(&mut self, ip: &Ipv4Addr) -> Option<String> {
+        if let Some(name) = self.get_cached(ip) {
+            return name;
+        }
+
+        let name = lookup(ip, self.mdns_timeout);
+
+        self.cache.insert(*ip, (name.clone(), Instant::now()));
+
+        name
+    }
+
+    // Resolves the addresses those are not cached concurrently, so a scan waits
+    // for the mDNS timeout once per batch instead of once per host
+    pub fn prefetch(&mut self, ips: &[Ipv4Addr]) {
+        let mut missing: Vec<Ipv4Addr> = ips.iter().filter(|ip| self.get_cached(ip).is_none()).cloned().collect();
+        missing.sort();
+        missing.dedup();
+
+        for batch in missing.chunks(MAX_QUERIES) {
+            let timeout = self.mdns_timeout;
+            let queries: Vec<(Ipv4Addr, thread::JoinHandle<Option<String>>)> = batch.iter()
+                .map(|&ip| (ip, thread::spawn(move || lookup(&ip, timeout))))
+                .collect();
+
+            for (ip, query) in queries {
+                let name = query.join().unwrap_or(None);
+                self.cache.insert(ip, (name, Instant::now()));
+            }
+        }
+    }
+
+    fn get_cached(&self, ip: &Ipv4Addr) -> Option<Option<String>> {
+        match self.cache.get(ip) {
+            Some((name, time)) if time.elapsed() < self.ttl => Some(name.clone()),
+            _ => None,
+        }
+    }
+}
+
+fn lookup(ip: &Ipv4Addr, mdns_timeout: Duration) -> Option<String> {
+    let name = match reverse_dns(ip) {
+        Some(r) => Some(r),
+        None => query_mdns(ip, mdns_timeout),
+    };
+
+    debug!("[resolve::lookup()]: {} resolved to {:?}", ip, name);
+
+    name
+}
+
+// Uses 'getent' to go through the NSS, so '/etc/hosts' and 'nss-mdns' are respected
+pub fn reverse_dns(ip: &Ipv4Addr) -> Option<String> {
+    let output = match Command::new("getent").arg("hosts").arg(ip.to_string()).output() {
+        Ok(r) => r,
+        Err(e) => {
+            debug!("[resolve::reverse_dns()]: getent run error: {}", e);
+            return None;
+        }
+    };
+
+    if !output.status.success() {
+        return None;
+    }
+
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .nth(1)
+        .map(|s| s.to_string())
+}
+
+// Sends a legacy unicast PTR query to the mDNS responder of the host
+pub fn query_mdns(ip: &Ipv4Addr, timeout: Duration) -> Option<String> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.set_read_timeout(Some(timeout)).ok()?;
+
+    let o = ip.octets();
+    let name = format!("{}.{}.{}.{}.in-addr.arpa", o[3], o[2], o[1], o[0]);
+
+    let target = SocketAddr::from((*ip, MDNS_PORT));
+    socket.send_to(&build_query(0x4e4c, &name), target).ok()?;
+
+    let mut buf = [0u8; 1500];
+    let deadline = Instant::now() + timeout;
+
+    while Instant::now() < deadline {
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(_) => return None,
+        };
+        if from.ip() != target.ip() {
+            continue;
+        }
+        if let Some(r) = parse_ptr_response(&buf[..len]) {
+            return Some(r);
+        }
+    }
+
+    None
+}
+
+pub fn build_query(id: u16, name: &str) -> Vec<u8> {
+    let mut packet: Vec<u8> = vec![];
+
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&[0, 0]); // Flags: standard query
+    packet.extend_from_slice(&[0, 1]); // QDCOUNT
+    packet.extend_from_slice(&[0, 0, 0, 0, 0, 0]); // ANCOUNT, NSCOUNT, ARCOUNT
+
+    for label in name.split('.') {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+
+    packet.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    packet
+}
+
+// Returns the first PTR answer of a DNS response
+pub fn parse_ptr_response(packet: &[u8]) -> Option<String> {
+    if packet.len() < 12 || packet[2] & 0x80 == 0 {
+        return None;
+    }
+
+    let qdcount = u16::from_be_bytes([packet[4], packet[5]]);
+    let ancount = u16::from_be_bytes([packet[6], packet[7]]);
+
+    let mut pos = 12;
+
+    for _ in 0..qdcount {
+        let (_, next) = read_name(packet, pos)?;
+        pos = next + 4;
+    }
+
+    for _ in 0..ancount {
+        let (_, next) = read_name(packet, pos)?;
+        let rtype = u16::from_be_bytes([*packet.get(next)?, *packet.get(next + 1)?]);
+        let rdlen = u16::from_be_bytes([*packet.get(next + 8)?, *packet.get(next + 9)?]) as usize;
+        let rdata = next + 10;
+
+        if rtype == TYPE_PTR {
+            return read_name(packet, rdata).map(|(name, _)| name);
+        }
+
+        pos = rdata + rdlen;
+    }
+
+    None
+}
+
+// Returns the A records of a DNS response, i.e. the mDNS announcements
+pub fn parse_a_records(packet: &[u8]) -> Vec<(String, Ipv4Addr)> {
+    let mut records: Vec<(String, Ipv4Addr)> = vec![];
+
+    if packet.len() < 12 || packet[2] & 0x80 == 0 {
+        return records;
+    }
+
+    let qdcount = u16::from_be_bytes([packet[4], packet[5]]);
+    let rrcount = u16::from_be_bytes([packet[6], packet[7]]) as usize
+        + u16::from_be_bytes([packet[8], packet[9]]) as usize
+        + u16::from_be_bytes([packet[10], packet[11]]) as usize;
+
+    let mut pos = 12;
+
+    for _ in 0..qdcount {
+        match read_name(packet, pos) {
+            Some((_, next)) => pos = next + 4,
+            None => return records,
+        }
+    }
+
+    for _ in 0..rrcount {
+        let (name, next) = match read_name(packet, pos) {
+            Some(r) => r,
+            None => break,
+        };
+        let header = match packet.get(next..next + 10) {
+            Some(r) => r,
+            None => break,
+        };
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let rdlen = u16::from_be_bytes([header[8], header[9]]) as usize;
+        let rdata = next + 10;
+
+        if rtype == TYPE_A && rdlen == 4 {
+            if let Some(a) = packet.get(rdata..rdata + 4) {
+                records.push((name, Ipv4Addr::new(a[0], a[1], a[2], a[3])));
+            }
+        }
+
+        pos = rdata + rdlen;
+    }
+
+    records
+}
+
+// Reads a (possibly compressed) domain name, returns it with the position after it
+pub fn read_name(packet: &[u8], start: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = vec![];
+    let mut pos = start;
+    let mut end: Option<usize> = None;
+    let mut jumps = 0;
+
+    loop {
+        let len = *packet.get(pos)? as usize;
+
+        if len == 0 {
+            pos += 1;
+            break;
+        }
+
+        if len & 0xC0 == 0xC0 {
+            let pointer = ((len & 0x3F) << 8) | *packet.get(pos + 1)? as usize;
+            if end.is_none() {
+                end = Some(pos + 2);
+            }
+            jumps += 1;
+            if jumps > 16 {
+                return None;
+            }
+            pos = pointer;
+            continue;
+        }
+
+        let label = packet.get(pos + 1..pos + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).to_string());
+        pos += 1 + len;
+    }
+
+    Some((labels.join("."), end.unwrap_or(pos)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(n: &str) -> Vec<u8> {
+        let mut b: Vec<u8> = vec![];
+        for label in n.split('.') {
+            b.push(label.len() as u8);
+            b.extend_from_slice(label.as_bytes());
+        }
+        b.push(0);
+        b
+    }
+
+    fn record(owner: &[u8], rtype: u16, rdata: &[u8]) -> Vec<u8> {
+        let mut b = owner.to_vec();
+        b.extend_from_slice(&rtype.to_be_bytes());
+        b.extend_from_slice(&[0x80, 0x01, 0, 0, 0, 120]);
+        b.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        b.extend_from_slice(rdata);
+        b
+    }
+
+    // Response to the query of 'build_query()', the answer points to the question
+    fn ptr_response(target: &str) -> Vec<u8> {
+        let mut b = build_query(0x4e4c, "20.1.168.192.in-addr.arpa");
+        b[2] = 0x84;
+        b[7] = 1;
+        b.extend(record(&[0xc0, 0x0c], TYPE_PTR, &name(target)));
+        b
+    }
+
+    #[test]
+    fn build_query_writes_a_ptr_question() {
+        let packet = build_query(0x4e4c, "20.1.168.192.in-addr.arpa");
+
+        assert_eq!(&packet[..12], &[0x4e, 0x4c, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&packet[12..packet.len() - 4], name("20.1.168.192.in-addr.arpa").as_slice());
+        assert_eq!(&packet[packet.len() - 4..], &[0, 12, 0, 1]);
+        assert_eq!(read_name(&packet, 12), Some(("20.1.168.192.in-addr.arpa".to_string(), packet.len() - 4)));
+    }
+
+    #[test]
+    fn parse_ptr_response_reads_the_answer() {
+        assert_eq!(parse_ptr_response(&ptr_response("Living-Room-TV.local")), Some("Living-Room-TV.local".to_string()));
+
+        // The question itself is not an answer
+        assert_eq!(parse_ptr_response(&build_query(0x4e4c, "20.1.168.192.in-addr.arpa")), None);
+
+        let packet = ptr_response("Living-Room-TV.local");
+        for len in &[0, 11, 40, packet.len() - 3] {
+            assert_eq!(parse_ptr_response(&packet[..*len]), None, "truncated at {}", len);
+        }
+    }
+
+    #[test]
+    fn parse_a_records_reads_every_answer() {
+        let mut packet: Vec<u8> = vec![0, 0, 0x84, 0, 0, 0, 0, 2, 0, 0, 0, 1];
+        packet.extend(record(&name("Furkans-iPhone.local"), TYPE_A, &[192, 168, 1, 20]));
+        packet.extend(record(&[0xc0, 0x0c], TYPE_PTR, &name("Furkans-iPhone.local")));
+        packet.extend(record(&[0xc0, 0x0c], TYPE_A, &[169, 254, 1, 20]));
+
+        assert_eq!(parse_a_records(&packet), vec![
+            ("Furkans-iPhone.local".to_string(), Ipv4Addr::new(192, 168, 1, 20)),
+            ("Furkans-iPhone.local".to_string(), Ipv4Addr::new(169, 254, 1, 20)),
+        ]);
+
+        // A query, and a response cut in the second record
+        let mut query = packet.clone();
+        query[2] = 0;
+        assert!(parse_a_records(&query).is_empty());
+        assert_eq!(parse_a_records(&packet[..50]).len(), 1);
+    }
+
+    #[test]
+    fn read_name_rejects_pointer_loops() {
+        let packet = [0u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xc0, 0x0c];
+        assert_eq!(read_name(&packet, 12), None);
+    }
+
+    #[test]
+    fn resolver_uses_the_cache_until_the_ttl() {
+        let ip = Ipv4Addr::new(192, 0, 2, 1);
+
+        let mut resolver = Resolver::new(Duration::from_secs(60), Duration::from_millis(1));
+        resolver.cache.insert(ip, (Some("cached.local".to_string()), Instant::now()));
+        assert_eq!(resolver.resolve(&ip), Some("cached.local".to_string()));
+
+        // Cached failures are not queried again either
+        resolver.cache.insert(ip, (None, Instant::now()));
+        assert_eq!(resolver.get_cached(&ip), Some(None));
+
+        let mut expired = Resolver::new(Duration::from_millis(0), Duration::from_millis(1));
+        expired.cache.insert(ip, (Some("cached.local".to_string()), Instant::now()));
+        assert_eq!(expired.get_cached(&ip), None);
+    }
+}
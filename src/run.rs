@@ -5,55 +5,46 @@
 // file LICENSE, which is part of this source code package, for details.
 // ====================================================
 
-use crate::cli::Args;
-use crate::error::Result;
+use crate::cli::{Args, Command as Subcommand};
+use crate::error::{Error, Result};
 
+use crate::check;
 use crate::config;
 use crate::db;
-use crate::net;
+use crate::monitor::Monitor;
+use crate::oui;
+use crate::replay;
+use crate::signal;
 
 use crate::log;
 
-use std::{thread, time};
+use chrono::prelude::*;
 
-use std::io::Write;
+use std::fs;
+use std::io::{self, Write};
 
-use std::process::exit;
 use std::process::Command;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
 
-pub enum ExitCodes {
-    RootRequired = 1,
-    ConfigFileDoesNotExist = 2,
-    ConfigInvalid = 3,
-    NmapNotInstalled = 4,
-    NmapRunError = 5,
-    ResultWriteError = 6,
-    RootCheckError = 7,
-    RootCommandError = 8,
-    HostnameNotFound = 9,
-    HostnameRunError = 10,
-    HostnameParseError = 11,
-    DBCreateError = 12,
-}
+use pnet::util::MacAddr;
 
 //Ref: https://github.com/max-wittig/bernard/blob/master/src/main.rs#L120
-fn is_root() -> bool {
+fn is_root() -> Result<bool> {
     let output = match Command::new("id").arg("-u").output() {
         Ok(r) => r,
-        Err(_) => {
-            exit(ExitCodes::RootCommandError as i32);
-        }
+        Err(e) => return Err(Error::RootCheck(format!("unable to run 'id': {}", e))),
     };
     let id: i32 = match String::from_utf8_lossy(&output.stdout)
         .trim()
         .parse::<i32>()
     {
         Ok(r) => r,
-        Err(_) => {
-            exit(ExitCodes::RootCheckError as i32);
-        }
+        Err(e) => return Err(Error::RootCheck(format!("unable to parse the output of 'id': {}", e))),
     };
-    id == 0
+    Ok(id == 0)
 }
 
 fn init_logger(verbose: u64, quiet: bool) {
@@ -78,60 +69,155 @@ fn init_logger(verbose: u64, quiet: bool) {
         .init();
 }
 
+fn list_alerts(db: &String, tag: &Option<String>) -> Result<()> {
+    let conn = db::init_db(db)?;
+    let alerts = db::get_pending_alerts(&conn, tag.as_ref().map(|t| t.as_str()))?;
+
+    if alerts.is_empty() {
+        println!("No pending alerts");
+    }
+
+    for alert in alerts {
+        let time = Local.timestamp(alert.time, 0);
+        println!("[{}] {} ({}): {} - notified {} time(s)", time.format("%Y-%m-%d %H:%M:%S"), alert.kind, alert.severity, alert.message, alert.count);
+    }
+
+    Ok(())
+}
+
+fn list_history(db: &String, tag: &Option<String>, limit: u32) -> Result<()> {
+    let conn = db::init_db(db)?;
+    let logs = db::get_logs(&conn, tag.as_ref().map(|t| t.as_str()), limit)?;
+
+    if logs.is_empty() {
+        println!("No history");
+    }
+
+    // Oldest first, like a log file
+    for log in logs.iter().rev() {
+        let time = Local.timestamp(log.time, 0);
+        let network = log.network.as_ref().map(|n| format!(" on {}", n)).unwrap_or_default();
+        let tags = if log.tags.is_empty() { String::new() } else { format!(" [{}]", log.tags.join(", ")) };
+        println!("[{}] {}: {} - {} ({}, {}){}{}", time.format("%Y-%m-%d %H:%M:%S"), log.kind, log.name, log.device, log.ip, log.mac, network, tags);
+    }
+
+    Ok(())
+}
+
+fn ack_alerts(db: &String, mac: &Option<String>) -> Result<()> {
+    // Already validated by the cli
+    let mac = mac.as_ref().map(|m| MacAddr::from_str(m).unwrap());
+
+    let count = db::ack_alerts(&db::init_db(db)?, mac)?;
+
+    println!("{} alert(s) acknowledged", count);
+
+    Ok(())
+}
+
+fn check_config(path: &String) -> Result<()> {
+    let content = fs::read_to_string(path).map_err(|_| Error::Config(config::ErrorType::ReadError))?;
+
+    let problems = check::check_config(&content);
+
+    if problems.is_empty() {
+        println!("{} is valid", path);
+        return Ok(());
+    }
+
+    for p in &problems {
+        println!("{}:{}:{}: {}", path, p.position.line, p.position.col, p.message);
+    }
+
+    Err(Error::ConfigProblems(problems.len()))
+}
+
+fn update_oui(path: &String, url: &String) -> Result<()> {
+    let config = config::get_config(path)?;
+
+    let file = match config.general.oui_file {
+        Some(r) => r,
+        None => return Err(Error::Io(io::Error::new(io::ErrorKind::NotFound, "'oui_file' of 'general' is not set"))),
+    };
+
+    println!("Downloading {} to {}", url, file);
+
+    let count = oui::update(&file, url)?;
+
+    println!("{} vendors saved to {}", count, file);
+
+    Ok(())
+}
+
 pub fn run(args: Args) -> Result<()> {
     init_logger(args.verbose, args.quiet);
 
-    let is_root = is_root();
+    match args.command {
+        Subcommand::Alerts(ref tag) => return list_alerts(&args.path_output, tag),
+        Subcommand::History(ref tag, limit) => return list_history(&args.path_output, tag, limit),
+        Subcommand::Ack(ref mac) => return ack_alerts(&args.path_output, mac),
+        Subcommand::CheckConfig => return check_config(&args.path_config),
+        Subcommand::UpdateOui(ref url) => return update_oui(&args.path_config, url),
+        Subcommand::Monitor | Subcommand::Replay(_) => {}
+    }
+
+    let is_root = is_root()?;
 
     if !is_root {
         println!("Info: You can escalate privileges via 'sudo' to get more accurate results!")
     }
 
-    let mut config = match config::get_config(&args.path_config) {
-        Ok(r) => r,
-        Err(config::ErrorType::ParseError) => {
-            error!("Config is invalid. Please make sure that only valid MAC addresses are used!");
-            exit(ExitCodes::ConfigInvalid as i32);
-        }
-        Err(config::ErrorType::SerdeError) => {
-            error!("Config file does not in YAML format!");
-            exit(ExitCodes::ConfigInvalid as i32);
-        }
-        Err(config::ErrorType::ReadError) => {
-            error!("Config file does not exist at the given location!");
-            exit(ExitCodes::ConfigFileDoesNotExist as i32);
-        }
-    };
+    let mut config = config::get_config(&args.path_config)?;
 
     info!("Config file loaded successfully!");
 
     config.is_root = is_root;
 
-    let conf = config.clone();
-    let duration = time::Duration::from_millis(config.general.interval);
-
-    let macmap = config::get_mac_info_map(config);
-    let gateway = net::get_gateway();
-
-    let ipmask = args.network.clone();
-    let hostname = net::get_hostname_addr().unwrap();
-
-    let mut olds: Vec<db::Host> = vec![];
+    if let Subcommand::Replay(ref dir) = args.command {
+        return replay::replay(dir, &config, &args.path_output);
+    }
 
-    let scanner: std::thread::JoinHandle<()> = std::thread::spawn(move || loop {
-        let res_nmap = net::do_scan_nmap(&ipmask, 5);
-        let res_arp = net::do_scan_arp();
+    let mut builder = Monitor::builder().store(&args.path_output).config_file(&args.path_config);
+
+    // The network of the cli is scanned through the interfaces of the cli or the 'general'
+    if !args.network.is_empty() {
+        let interfaces = if args.interfaces.is_empty() {
+            config.general.interfaces.clone()
+        } else {
+            args.interfaces.clone()
+        };
+        builder = builder.network(config::Network::new(&args.network, interfaces));
+    } else if !args.interfaces.is_empty() {
+        // Every network of the config must have an address on them, see 'Error::Interface'
+        for network in config.networks.iter_mut() {
+            network.interfaces = args.interfaces.clone();
+        }
+    }
 
-        let news = db::migrate_to_host_list(&macmap, &gateway, res_nmap, res_arp, hostname);
+    signal::install();
 
-        db::get_notifies(&olds, &news, &conf, &args.path_output);
+    let handle = builder.config(config).build()?.start()?;
 
-        olds = news.clone();
+    for d in handle.degraded() {
+        println!("Warning: {} has no default route, the gateway checks are disabled", d);
+    }
 
-        thread::sleep(duration);
+    // The current scan is finished and the queued events are stored before exiting
+    let stop = handle.stopper();
+    let reload = handle.reloader();
+    thread::spawn(move || loop {
+        if signal::reload_requested() {
+            reload.store(true, Ordering::SeqCst);
+        }
+        if signal::stop_requested() {
+            println!("Stopping after the current scan, send the signal again to force");
+            signal::reset();
+            stop.store(true, Ordering::SeqCst);
+            return;
+        }
+        thread::sleep(Duration::from_millis(200));
     });
 
-    let _s = scanner.join();
-
-    Ok(())
+    // Nobody listens the events, the notifications are shown by the monitor itself
+    handle.wait()
 }
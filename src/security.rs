@@ -0,0 +1,415 @@
+// ====================================================
+// Netlyser Copyright(C) 2019 Furkan Türkal
+// This program comes with ABSOLUTELY NO WARRANTY; This is free software,
+// and you are welcome to redistribute it under certain conditions; See
+// file LICENSE, which is part of this source code package, for details.
+// ====================================================
+
+use std::{
+    collections::HashMap,
+    net::Ipv4Addr,
+    str::FromStr,
+};
+
+use pnet::util::MacAddr;
+
+use crate::config;
+use crate::db;
+use crate::net;
+
+use rusqlite::Connection;
+
+use notify_rust::{Notification, NotificationHint, NotificationUrgency};
+
+// Raises an intruder alarm for every device out of the config when in allowlist mode
+pub fn check_allowlist(conn: &Connection, hosts: &Vec<db::Host>, conf: &config::Config, time: i64) -> Vec<db::Alert> {
+    let mut alerts: Vec<db::Alert> = vec![];
+
+    if conf.security.mode != config::SecurityMode::Allowlist {
+        return alerts;
+    }
+
+    for h in hosts {
+        if h.known || h.mac == MacAddr::zero() {
+            continue;
+        }
+
+        let message = format!("Device out of the allowlist: {} ({})", h.mac, h.ip);
+
+        alerts.extend(raise(conn, "intruder", h.ip, h.mac, &message, &conf, time));
+    }
+
+    alerts
+}
+
+// Re-arms the acknowledged alerts of the devices those left since the last scan
+pub fn rearm_alerts(conn: &Connection, olds: &Vec<db::Host>, news: &Vec<db::Host>) {
+    for old in olds {
+        if old.mac == MacAddr::zero() || news.iter().any(|n| n.mac == old.mac) {
+            continue;
+        }
+
+        match db::rearm_alerts(conn, old.mac) {
+            Ok(0) => {}
+            Ok(n) => info!("[security::rearm_alerts()]: {} left, re-armed {} alert(s)", old.mac, n),
+            Err(e) => warn!("[security::rearm_alerts()]: error throwed when running 'rearm_alerts()' function. Err: {}, ", e),
+        }
+    }
+}
+
+// Watches the ARP table between the scans to detect spoofing attempts:
+// * An IP claimed by more than one MAC
+// * Gateway MAC changing
+// * A MAC answering for too many IPs
+pub struct ArpWatch {
+    // Interfaces without a default route have no gateway to watch
+    gateway: Option<net::Gateway>,
+    // MAC the gateway is moved to, with the number of the scans in a row it is seen
+    candidate: Option<(MacAddr, usize)>,
+    last: HashMap<Ipv4Addr, MacAddr>,
+}
+
+impl ArpWatch {
+    pub fn new(gateway: Option<net::Gateway>) -> ArpWatch {
+        ArpWatch {
+            gateway: gateway,
+            candidate: None,
+            last: HashMap::new(),
+        }
+    }
+
+    // The learned MAC is kept if the gateway is the same, i.e. a route snapshot
+    // of a replay that only has the IP
+    pub fn set_gateway(&mut self, gateway: Option<net::Gateway>) {
+        self.gateway = match (self.gateway.take(), gateway) {
+            (Some(ref old), Some(ref new)) if old.ip == new.ip && new.mac == MacAddr::zero() => Some(old.clone()),
+            (_, new) => {
+                self.candidate = None;
+                new
+            }
+        };
+    }
+
+    // Returns the alerts those are raised for the first time
+    pub fn check(&mut self, conn: &Connection, entries: &Vec<net::ArpEntry>, conf: &config::Config, time: i64) -> Vec<db::Alert> {
+        let mut alerts: Vec<db::Alert> = vec![];
+
+        if !conf.security.arp_watch {
+            return alerts;
+        }
+
+        let mut ips: HashMap<Ipv4Addr, Vec<MacAddr>> = HashMap::new();
+        let mut macs: HashMap<MacAddr, Vec<Ipv4Addr>> = HashMap::new();
+
+        for entry in entries {
+            let m = ips.entry(entry.ip).or_insert_with(Vec::new);
+            if !m.contains(&entry.mac) {
+                m.push(entry.mac);
+            }
+            let i = macs.entry(entry.mac).or_insert_with(Vec::new);
+            if !i.contains(&entry.ip) {
+                i.push(entry.ip);
+            }
+        }
+
+        // Learn the gateway MAC if it was not resolved at the startup
+        if let Some(ref mut gateway) = self.gateway {
+            match ips.get(&gateway.ip) {
+                Some(ms) if gateway.mac == MacAddr::zero() => gateway.mac = ms[0],
+                // A replaced router is accepted once its alert is acknowledged, or when
+                // it is the only MAC of the gateway for enough scans in a row
+                Some(ms) if ms.len() == 1 && ms[0] != gateway.mac => {
+                    let count = match self.candidate {
+                        Some((mac, count)) if mac == ms[0] => count + 1,
+                        _ => 1,
+                    };
+
+                    let acked = match db::is_alert_acked(conn, "gateway_changed", ms[0]) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            warn!("[security::check()]: error throwed when running 'is_alert_acked()' function. Err: {}, ", e);
+                            false
+                        }
+                    };
+
+                    if acked || (conf.security.gateway_relearn != 0 && count >= conf.security.gateway_relearn) {
+                        info!("[security::check()]: gateway {} MAC is changed from {} to {}", gateway.ip, gateway.mac, ms[0]);
+                        gateway.mac = ms[0];
+                        self.candidate = None;
+                    } else {
+                        self.candidate = Some((ms[0], count));
+                    }
+                }
+                _ => self.candidate = None,
+            }
+        }
+
+        for (ip, ms) in &ips {
+            if ms.len() > 1 {
+                let list: Vec<String> = ms.iter().map(|m| m.to_string()).collect();
+                let message = format!("IP {} is claimed by multiple MACs: {}", ip, list.join(", "));
+                alerts.extend(raise(conn, "ip_conflict", *ip, ms[1], &message, &conf, time));
+            }
+
+            if let Some(ref gateway) = self.gateway {
+                if *ip == gateway.ip && !ms.contains(&gateway.mac) {
+                    let message = format!("Gateway {} MAC changed from {} to {}", ip, gateway.mac, ms[0]);
+                    alerts.extend(raise(conn, "gateway_changed", *ip, ms[0], &message, &conf, time));
+                }
+            }
+
+            // A device getting the IP of a gone one is just a DHCP reassignment,
+            // so the move is suspicious only if the previous owner is still alive
+            if let Some(old) = self.last.get(ip) {
+                if !ms.contains(old) && macs.contains_key(old) {
+                    let message = format!("IP {} moved from {} to {}", ip, old, ms[0]);
+                    alerts.extend(raise(conn, "ip_conflict", *ip, ms[0], &message, &conf, time));
+                }
+            }
+        }
+
+        for (mac, is) in &macs {
+            if is.len() > conf.security.max_ips_per_mac {
+                let list: Vec<String> = is.iter().map(|i| i.to_string()).collect();
+                let message = format!("MAC {} answers for multiple IPs: {}", mac, list.join(", "));
+                alerts.extend(raise(conn, "mac_multiple_ips", is[0], *mac, &message, &conf, time));
+            }
+        }
+
+        self.last = net::get_arp_map(entries);
+
+        alerts
+    }
+}
+
+// The severity is taken from the groups of the device, if it is a known one
+fn raise(conn: &Connection, kind: &str, ip: Ipv4Addr, mac: MacAddr, message: &str, conf: &config::Config, time: i64) -> Option<db::Alert> {
+    let tags = match db::get_device_tags(conn, mac) {
+        Ok(r) => r,
+        Err(e) => {
+            warn!("[security::raise()]: error throwed when running 'get_device_tags()' function. Err: {}, ", e);
+            vec![]
+        }
+    };
+
+    match db::raise_alert(conn, kind, conf.get_severity(&tags), &tags, ip, mac, &message, time) {
+        Ok(Some(alert)) => {
+            warn!("[security::raise()]: {}", message);
+            Some(alert)
+        }
+        Ok(None) => None,
+        Err(e) => {
+            warn!("[security::raise()]: error throwed when running 'raise_alert()' function. Err: {}, ", e);
+            None
+        }
+    }
+}
+
+// Returns the unacknowledged alerts those must be notified again as the repeat interval is elapsed
+pub fn repeat_alerts(conn: &Connection, conf: &config::Config, time: i64) -> Vec<db::Alert> {
+    let alerts = match db::get_due_alerts(conn, conf.security.repeat_interval, time) {
+        Ok(r) => r,
+        Err(e) => {
+            warn!("[security::repeat_alerts()]: error throwed when running 'get_due_alerts()' function. Err: {}, ", e);
+            return vec![];
+        }
+    };
+
+    for alert in &alerts {
+        if let Err(e) = db::set_alert_notified(conn, alert.id, time) {
+            warn!("[security::repeat_alerts()]: error throwed when running 'set_alert_notified()' function. Err: {}, ", e);
+        }
+    }
+
+    alerts
+}
+
+pub fn notify_alert(alert: &db::Alert, dry_run: bool) {
+    let not: String = format!("{}\nRun 'netlyser ack {}' to acknowledge", alert.message, alert.mac);
+    if dry_run {
+        db::print_notify(&format!("ALERT: {}!", alert.kind.to_uppercase()), &not);
+        return;
+    }
+    let urgency = match config::Severity::from_str(&alert.severity) {
+        Ok(config::Severity::Low) => NotificationUrgency::Low,
+        Ok(config::Severity::Normal) => NotificationUrgency::Normal,
+        _ => NotificationUrgency::Critical,
+    };
+    db::show_notify(Notification::new()
+        .appname("Netlyser")
+        .summary(&format!("ALERT: {}!", alert.kind.to_uppercase()))
+        .body(&not)
+        .hint(NotificationHint::Category(format!("x-netlyser.{}", alert.kind)))
+        .urgency(urgency)
+        .timeout(0));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{env, fs, process};
+
+    use crate::serde_yaml;
+
+    fn get_db(name: &str) -> Connection {
+        let path = env::temp_dir().join(format!("netlyser-{}-{}.db", name, process::id()));
+        let _ = fs::remove_file(&path);
+        db::init_db(&path.to_string_lossy().to_string()).unwrap()
+    }
+
+    fn get_config(security: &str) -> config::Config {
+        let content = format!("general:\n  interval: 1000\n  round: 1\n  notify_on_connect: true\n  notify_on_disconnect: true\nhosts: []\nsecurity:\n{}", security);
+        serde_yaml::from_str(&content).unwrap()
+    }
+
+    fn get_host(ip: &str, mac: &str) -> db::Host {
+        let mut h = db::Host::new();
+        h.set_ip(ip.parse().unwrap());
+        h.set_mac(MacAddr::from_str(mac).unwrap());
+        h
+    }
+
+    #[test]
+    fn acked_alert_is_not_raised_again_while_present() {
+        let db = get_db("ack");
+        let conf = get_config("  mode: allowlist\n");
+        let hosts = vec![get_host("192.168.1.20", "02:00:00:00:00:20")];
+
+        assert_eq!(check_allowlist(&db, &hosts, &conf, 0).len(), 1);
+        assert_eq!(db::ack_alerts(&db, None).unwrap(), 1);
+
+        // Rescan while the device is still there
+        rearm_alerts(&db, &hosts, &hosts);
+        assert!(check_allowlist(&db, &hosts, &conf, 0).is_empty());
+
+        // Leaves, then comes back
+        rearm_alerts(&db, &hosts, &vec![]);
+        assert_eq!(check_allowlist(&db, &hosts, &conf, 0).len(), 1);
+    }
+
+    fn get_entry(ip: &str, mac: &str) -> net::ArpEntry {
+        net::ArpEntry {
+            ip: ip.parse().unwrap(),
+            mac: MacAddr::from_str(mac).unwrap(),
+            device: "eth0".to_string(),
+        }
+    }
+
+    fn get_watch() -> ArpWatch {
+        ArpWatch::new(Some(net::Gateway {
+            ip: Ipv4Addr::new(192, 168, 1, 1),
+            mac: MacAddr::from_str("50:c7:bf:00:11:22").unwrap(),
+        }))
+    }
+
+    fn kinds(alerts: &Vec<db::Alert>) -> Vec<&str> {
+        alerts.iter().map(|a| a.kind.as_str()).collect()
+    }
+
+    #[test]
+    fn arp_watch_reports_ip_conflicts() {
+        let db = get_db("ip-conflict");
+        let conf = get_config("  arp_watch: true\n");
+        let mut watch = get_watch();
+
+        let claimed = vec![get_entry("192.168.1.20", "02:00:00:00:00:20"), get_entry("192.168.1.20", "02:00:00:00:00:21")];
+        let alerts = watch.check(&db, &claimed, &conf, 0);
+        assert_eq!(kinds(&alerts), vec!["ip_conflict"]);
+        assert_eq!(alerts[0].mac, "02:00:00:00:00:21");
+
+        // The IP is taken over while the previous owner still answers
+        let mut watch = get_watch();
+        watch.check(&db, &vec![get_entry("192.168.1.30", "02:00:00:00:00:30")], &conf, 0);
+        let moved = vec![get_entry("192.168.1.30", "02:00:00:00:00:31"), get_entry("192.168.1.31", "02:00:00:00:00:30")];
+        assert_eq!(kinds(&watch.check(&db, &moved, &conf, 0)), vec!["ip_conflict"]);
+
+        // Reassigned by DHCP after the previous owner left
+        let mut watch = get_watch();
+        watch.check(&db, &vec![get_entry("192.168.1.40", "02:00:00:00:00:40")], &conf, 0);
+        assert!(watch.check(&db, &vec![get_entry("192.168.1.40", "02:00:00:00:00:41")], &conf, 0).is_empty());
+    }
+
+    #[test]
+    fn arp_watch_reports_gateway_changes() {
+        let db = get_db("gateway-changed");
+        let conf = get_config("  gateway_relearn: 0\n");
+        let mut watch = get_watch();
+
+        assert!(watch.check(&db, &vec![get_entry("192.168.1.1", "50:c7:bf:00:11:22")], &conf, 0).is_empty());
+
+        let spoofed = vec![get_entry("192.168.1.1", "02:00:00:00:66:66")];
+        let alerts = watch.check(&db, &spoofed, &conf, 0);
+        assert_eq!(kinds(&alerts), vec!["gateway_changed"]);
+        assert_eq!(alerts[0].mac, "02:00:00:00:66:66");
+
+        // Pending, and never accepted without the ack
+        for _ in 0..20 {
+            assert!(watch.check(&db, &spoofed, &conf, 0).is_empty());
+        }
+        assert_eq!(watch.gateway.as_ref().unwrap().mac, MacAddr::from_str("50:c7:bf:00:11:22").unwrap());
+    }
+
+    #[test]
+    fn arp_watch_relearns_the_gateway_once_acked() {
+        let db = get_db("gateway-acked");
+        let conf = get_config("  gateway_relearn: 0\n");
+        let mut watch = get_watch();
+        let router = vec![get_entry("192.168.1.1", "02:00:00:00:77:77")];
+
+        assert_eq!(kinds(&watch.check(&db, &router, &conf, 0)), vec!["gateway_changed"]);
+        assert_eq!(db::ack_alerts(&db, None).unwrap(), 1);
+
+        assert!(watch.check(&db, &router, &conf, 0).is_empty());
+        assert_eq!(watch.gateway.as_ref().unwrap().mac, router[0].mac);
+
+        // The old router is a change too from now on
+        let old = vec![get_entry("192.168.1.1", "50:c7:bf:00:11:22")];
+        assert_eq!(kinds(&watch.check(&db, &old, &conf, 0)), vec!["gateway_changed"]);
+    }
+
+    #[test]
+    fn arp_watch_relearns_the_gateway_after_consistent_scans() {
+        let db = get_db("gateway-relearn");
+        let conf = get_config("  gateway_relearn: 3\n");
+        let mut watch = get_watch();
+        let router = vec![get_entry("192.168.1.1", "02:00:00:00:88:88")];
+        let both = vec![get_entry("192.168.1.1", "02:00:00:00:88:88"), get_entry("192.168.1.1", "50:c7:bf:00:11:22")];
+
+        assert_eq!(kinds(&watch.check(&db, &router, &conf, 0)), vec!["gateway_changed"]);
+        watch.check(&db, &router, &conf, 0);
+
+        // Both answering is not a consistent scan, the count starts over
+        watch.check(&db, &both, &conf, 0);
+        watch.check(&db, &router, &conf, 0);
+        watch.check(&db, &router, &conf, 0);
+        assert_eq!(watch.gateway.as_ref().unwrap().mac, MacAddr::from_str("50:c7:bf:00:11:22").unwrap());
+
+        watch.check(&db, &router, &conf, 0);
+        assert_eq!(watch.gateway.as_ref().unwrap().mac, router[0].mac);
+    }
+
+    #[test]
+    fn arp_watch_reports_macs_with_too_many_ips() {
+        let db = get_db("max-ips");
+        let entries = vec![get_entry("192.168.1.50", "02:00:00:00:00:50"), get_entry("192.168.1.51", "02:00:00:00:00:50")];
+
+        let alerts = get_watch().check(&db, &entries, &get_config("  max_ips_per_mac: 1\n"), 0);
+        assert_eq!(kinds(&alerts), vec!["mac_multiple_ips"]);
+        assert_eq!(alerts[0].mac, "02:00:00:00:00:50");
+
+        assert!(get_watch().check(&db, &entries, &get_config("  max_ips_per_mac: 2\n"), 0).is_empty());
+        assert!(get_watch().check(&db, &entries, &get_config("  arp_watch: false\n"), 0).is_empty());
+    }
+
+    #[test]
+    fn pending_alert_is_not_raised_twice() {
+        let db = get_db("pending");
+        let conf = get_config("  mode: allowlist\n");
+        let hosts = vec![get_host("192.168.1.21", "02:00:00:00:00:21")];
+
+        assert_eq!(check_allowlist(&db, &hosts, &conf, 0).len(), 1);
+        rearm_alerts(&db, &hosts, &vec![]);
+        assert!(check_allowlist(&db, &hosts, &conf, 0).is_empty());
+    }
+}
@@ -0,0 +1,48 @@
+// ====================================================
+// Netlyser Copyright(C) 2019 Furkan Türkal
+// This program comes with ABSOLUTELY NO WARRANTY; This is free software,
+// and you are welcome to redistribute it under certain conditions; See
+// file LICENSE, which is part of this source code package, for details.
+// ====================================================
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use libc::{c_int, sighandler_t, SIGHUP, SIGINT, SIGTERM, SIG_DFL};
+
+// Only an atomic store is safe in a signal handler, the flag is polled by the caller
+static STOP: AtomicBool = AtomicBool::new(false);
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_stop(_: c_int) {
+    STOP.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn on_reload(_: c_int) {
+    RELOAD.store(true, Ordering::SeqCst);
+}
+
+// Catches SIGINT and SIGTERM instead of being killed by them, and SIGHUP to reload the config
+pub fn install() {
+    unsafe {
+        libc::signal(SIGINT, on_stop as extern "C" fn(c_int) as sighandler_t);
+        libc::signal(SIGTERM, on_stop as extern "C" fn(c_int) as sighandler_t);
+        libc::signal(SIGHUP, on_reload as extern "C" fn(c_int) as sighandler_t);
+    }
+}
+
+// Restores the default handlers, so a second signal kills the process
+pub fn reset() {
+    unsafe {
+        libc::signal(SIGINT, SIG_DFL);
+        libc::signal(SIGTERM, SIG_DFL);
+    }
+}
+
+pub fn stop_requested() -> bool {
+    STOP.load(Ordering::SeqCst)
+}
+
+// Every SIGHUP is reported once
+pub fn reload_requested() -> bool {
+    RELOAD.swap(false, Ordering::SeqCst)
+}
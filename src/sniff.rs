@@ -0,0 +1,397 @@
+// ====================================================
+// Netlyser Copyright(C) 2019 Furkan Türkal
+// This program comes with ABSOLUTELY NO WARRANTY; This is free software,
+// and you are welcome to redistribute it under certain conditions; See
+// file LICENSE, which is part of this source code package, for details.
+// ====================================================
+
+use std::{
+    fs::OpenOptions,
+    io::{Error, ErrorKind, Read},
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::Sender,
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use pnet::datalink::{self, Channel};
+use pnet::packet::arp::ArpPacket;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::udp::UdpPacket;
+use pnet::packet::Packet;
+use pnet::util::MacAddr;
+
+use crate::resolve;
+
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+const DHCP_MAGIC: [u8; 4] = [99, 130, 83, 99];
+const MDNS_PORT: u16 = 5353;
+
+// How long a read waits for a packet, so the stop request is noticed
+const READ_TIMEOUT: Duration = Duration::from_millis(500);
+
+// Read errors in a row those stop the listener, i.e. the interface is gone
+const MAX_READ_ERRORS: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    Arp,
+    Dhcp,
+    Mdns,
+    Lease,
+}
+
+// A host seen on the wire without scanning
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sighting {
+    pub ip: Ipv4Addr,
+    pub mac: MacAddr,
+    pub hostname: Option<String>,
+    pub client_id: Option<String>,
+    pub source: Source,
+}
+
+// Listens the ARP, DHCP and mDNS packets on the interface and sends the sightings
+// to 'tx' until it is stopped
+pub fn listen(interface: &str, tx: Sender<Sighting>, stop: Arc<AtomicBool>) -> Result<thread::JoinHandle<()>, Error> {
+    let iface = match datalink::interfaces().into_iter().find(|i| i.name == interface) {
+        Some(r) => r,
+        None => return Err(Error::new(ErrorKind::NotFound, format!("interface '{}' not found", interface))),
+    };
+
+    let config = datalink::Config {
+        read_timeout: Some(READ_TIMEOUT),
+        ..Default::default()
+    };
+
+    let mut rx = match datalink::channel(&iface, config)? {
+        Channel::Ethernet(_, rx) => rx,
+        _ => return Err(Error::new(ErrorKind::Other, "unsupported channel type")),
+    };
+
+    info!("[sniff::listen()]: listening on {}", interface);
+
+    let interface = interface.to_string();
+
+    Ok(thread::spawn(move || {
+        let mut errors: u32 = 0;
+
+        while !stop.load(Ordering::SeqCst) {
+            match rx.next() {
+                Ok(frame) => {
+                    errors = 0;
+                    for s in parse_frame(frame) {
+                        debug!("[sniff::listen()]: {:?}", s);
+                        if tx.send(s).is_err() {
+                            return;
+                        }
+                    }
+                }
+                Err(ref e) if e.kind() == ErrorKind::TimedOut => {}
+                Err(e) => {
+                    errors += 1;
+                    if errors >= MAX_READ_ERRORS {
+                        warn!("[sniff::listen()]: stopped listening on {} after {} read errors in a row. Err: {}", interface, errors, e);
+                        return;
+                    }
+                    warn!("[sniff::listen()]: unable to read the packet on {}, retrying. Err: {}", interface, e);
+                    thread::sleep(READ_TIMEOUT * errors);
+                }
+            }
+        }
+
+        info!("[sniff::listen()]: stopped listening on {}", interface);
+    }))
+}
+
+pub fn parse_frame(frame: &[u8]) -> Vec<Sighting> {
+    let eth = match EthernetPacket::new(frame) {
+        Some(r) => r,
+        None => return vec![],
+    };
+
+    match eth.get_ethertype() {
+        EtherTypes::Arp => parse_arp(eth.payload()).into_iter().collect(),
+        EtherTypes::Ipv4 => parse_ipv4(eth.get_source(), eth.payload()),
+        _ => vec![],
+    }
+}
+
+fn parse_arp(payload: &[u8]) -> Option<Sighting> {
+    let arp = ArpPacket::new(payload)?;
+    let ip = arp.get_sender_proto_addr();
+
+    // ARP probes of the hosts those have no address yet
+    if ip.is_unspecified() {
+        return None;
+    }
+
+    Some(Sighting {
+        ip: ip,
+        mac: arp.get_sender_hw_addr(),
+        hostname: None,
+        client_id: None,
+        source: Source::Arp,
+    })
+}
+
+fn parse_ipv4(mac: MacAddr, payload: &[u8]) -> Vec<Sighting> {
+    let ipv4 = match Ipv4Packet::new(payload) {
+        Some(r) => r,
+        None => return vec![],
+    };
+
+    if ipv4.get_next_level_protocol() != IpNextHeaderProtocols::Udp {
+        return vec![];
+    }
+
+    let udp = match UdpPacket::new(ipv4.payload()) {
+        Some(r) => r,
+        None => return vec![],
+    };
+
+    match (udp.get_source(), udp.get_destination()) {
+        (DHCP_CLIENT_PORT, DHCP_SERVER_PORT) | (DHCP_SERVER_PORT, DHCP_CLIENT_PORT) => {
+            parse_dhcp(udp.payload()).into_iter().collect()
+        }
+        (MDNS_PORT, _) => {
+            let source = ipv4.get_source();
+            resolve::parse_a_records(udp.payload())
+                .into_iter()
+                .filter(|(_, ip)| *ip == source)
+                .map(|(name, ip)| Sighting {
+                    ip: ip,
+                    mac: mac,
+                    hostname: Some(name),
+                    client_id: None,
+                    source: Source::Mdns,
+                })
+                .collect()
+        }
+        _ => vec![],
+    }
+}
+
+// Requests of the clients and the ACKs of the server both bind an IP to a MAC
+pub fn parse_dhcp(payload: &[u8]) -> Option<Sighting> {
+    // op, htype, hlen, hops, xid, secs, flags, ciaddr, yiaddr, siaddr, giaddr, chaddr, sname, file
+    if payload.len() < 240 || payload[236..240] != DHCP_MAGIC {
+        return None;
+    }
+
+    let ciaddr = Ipv4Addr::new(payload[12], payload[13], payload[14], payload[15]);
+    let yiaddr = Ipv4Addr::new(payload[16], payload[17], payload[18], payload[19]);
+    let c = &payload[28..34];
+    let mac = MacAddr::new(c[0], c[1], c[2], c[3], c[4], c[5]);
+
+    let mut kind: u8 = 0;
+    let mut requested: Option<Ipv4Addr> = None;
+    let mut hostname: Option<String> = None;
+    let mut client_id: Option<String> = None;
+
+    let mut pos = 240;
+    while pos < payload.len() {
+        let code = payload[pos];
+        if code == 0 {
+            pos += 1;
+            continue;
+        }
+        if code == 255 {
+            break;
+        }
+        let len = *payload.get(pos + 1)? as usize;
+        let data = payload.get(pos + 2..pos + 2 + len)?;
+        match code {
+            12 => hostname = Some(String::from_utf8_lossy(data).to_string()),
+            50 if len == 4 => requested = Some(Ipv4Addr::new(data[0], data[1], data[2], data[3])),
+            53 if len == 1 => kind = data[0],
+            61 => client_id = Some(data.iter().map(|b| format!("{:02x}", b)).collect::<Vec<String>>().join(":")),
+            _ => {}
+        }
+        pos += 2 + len;
+    }
+
+    // 3: DHCPREQUEST, 5: DHCPACK
+    let ip = match kind {
+        3 => requested.unwrap_or(ciaddr),
+        5 => yiaddr,
+        _ => return None,
+    };
+
+    if ip.is_unspecified() {
+        return None;
+    }
+
+    Some(Sighting {
+        ip: ip,
+        mac: mac,
+        hostname: hostname,
+        client_id: client_id,
+        source: Source::Dhcp,
+    })
+}
+
+// Reads the frames of a pcap (not pcapng) capture file, i.e. the fixtures of the tests
+pub fn read_pcap(path: &str) -> Result<Vec<Vec<u8>>, Error> {
+    let mut file = OpenOptions::new().read(true).write(false).open(path)?;
+    let mut content: Vec<u8> = vec![];
+    file.read_to_end(&mut content)?;
+
+    let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, format!("{}: {}", path, msg));
+
+    if content.len() < 24 {
+        return Err(invalid("truncated header"));
+    }
+
+    let swapped = match [content[0], content[1], content[2], content[3]] {
+        [0xd4, 0xc3, 0xb2, 0xa1] | [0x4d, 0x3c, 0xb2, 0xa1] => false,
+        [0xa1, 0xb2, 0xc3, 0xd4] | [0xa1, 0xb2, 0x3c, 0x4d] => true,
+        _ => return Err(invalid("not a pcap file")),
+    };
+
+    let read_u32 = |b: &[u8]| {
+        let v = [b[0], b[1], b[2], b[3]];
+        if swapped { u32::from_be_bytes(v) } else { u32::from_le_bytes(v) }
+    };
+
+    // LINKTYPE_ETHERNET
+    if read_u32(&content[20..24]) != 1 {
+        return Err(invalid("only ethernet captures are supported"));
+    }
+
+    let mut frames: Vec<Vec<u8>> = vec![];
+    let mut pos = 24;
+
+    while pos + 16 <= content.len() {
+        let len = read_u32(&content[pos + 8..pos + 12]) as usize;
+        let start = pos + 16;
+        match content.get(start..start + len) {
+            Some(r) => frames.push(r.to_vec()),
+            None => return Err(invalid("truncated packet")),
+        }
+        pos = start + len;
+    }
+
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    fn read(name: &str) -> Vec<Vec<u8>> {
+        read_pcap(&format!("data/fixtures/sniff/{}.pcap", name)).unwrap()
+    }
+
+    fn mac(s: &str) -> MacAddr {
+        MacAddr::from_str(s).unwrap()
+    }
+
+    // Payload of a UDP frame, after the ethernet, IPv4 (without options) and the UDP headers
+    fn udp_payload(frame: &[u8]) -> &[u8] {
+        &frame[14 + 20 + 8..]
+    }
+
+    #[test]
+    fn read_pcap_reads_every_frame() {
+        assert_eq!(read("arp").len(), 4);
+        assert_eq!(read("dhcp").len(), 5);
+        assert_eq!(read("mdns").len(), 3);
+    }
+
+    #[test]
+    fn read_pcap_rejects_truncated_and_other_files() {
+        assert_eq!(read_pcap("data/fixtures/sniff/truncated.pcap").unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(read_pcap("data/fixtures/nmap/scan.xml").unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn parse_frame_reads_arp() {
+        let frames = read("arp");
+
+        assert_eq!(parse_frame(&frames[0]), vec![Sighting {
+            ip: Ipv4Addr::new(192, 168, 1, 20),
+            mac: mac("01:23:45:67:89:ab"),
+            hostname: None,
+            client_id: None,
+            source: Source::Arp,
+        }]);
+
+        // Probe without an address
+        assert!(parse_frame(&frames[1]).is_empty());
+        // Truncated ARP and ethernet headers
+        assert!(parse_frame(&frames[2]).is_empty());
+        assert!(parse_frame(&frames[3]).is_empty());
+    }
+
+    #[test]
+    fn parse_frame_reads_dhcp() {
+        let frames = read("dhcp");
+
+        // Request with the hostname (12) and the client-id (61)
+        assert_eq!(parse_frame(&frames[0]), vec![Sighting {
+            ip: Ipv4Addr::new(192, 168, 1, 40),
+            mac: mac("02:00:00:00:00:10"),
+            hostname: Some("kids-tablet".to_string()),
+            client_id: Some("01:02:00:00:00:00:10".to_string()),
+            source: Source::Dhcp,
+        }]);
+
+        // ACK of the server is about the client
+        assert_eq!(parse_frame(&frames[1]), vec![Sighting {
+            ip: Ipv4Addr::new(192, 168, 1, 40),
+            mac: mac("02:00:00:00:00:10"),
+            hostname: None,
+            client_id: None,
+            source: Source::Dhcp,
+        }]);
+
+        // Discover does not bind an address
+        assert!(parse_frame(&frames[2]).is_empty());
+        // Truncated options and IP header
+        assert!(parse_frame(&frames[3]).is_empty());
+        assert!(parse_frame(&frames[4]).is_empty());
+    }
+
+    #[test]
+    fn parse_dhcp_rejects_short_and_foreign_payloads() {
+        let frames = read("dhcp");
+        let payload = udp_payload(&frames[0]);
+
+        assert!(parse_dhcp(payload).is_some());
+        assert!(parse_dhcp(&payload[..239]).is_none());
+
+        let mut foreign = payload.to_vec();
+        foreign[236] = 0;
+        assert!(parse_dhcp(&foreign).is_none());
+
+        assert!(parse_dhcp(udp_payload(&frames[3])).is_none());
+    }
+
+    #[test]
+    fn parse_frame_reads_mdns() {
+        let frames = read("mdns");
+
+        // The record of another address is not a sighting of the sender
+        assert_eq!(parse_frame(&frames[0]), vec![Sighting {
+            ip: Ipv4Addr::new(192, 168, 1, 20),
+            mac: mac("01:23:45:67:89:ab"),
+            hostname: Some("Furkans-iPhone.local".to_string()),
+            client_id: None,
+            source: Source::Mdns,
+        }]);
+
+        // Query and truncated response
+        assert!(parse_frame(&frames[1]).is_empty());
+        assert!(parse_frame(&frames[2]).is_empty());
+    }
+}